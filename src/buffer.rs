@@ -1,18 +1,33 @@
 //! Endpoint memory buffers
 //!
-//! A `USB` instance owns an `Allocator`. The `Allocator` hands-off
-//! `Buffer`s from a single, large byte collection. `Buffer`s support
-//! bulk, volatile reads and writes.
+//! A `USB` instance owns one or more `Allocator`s, organized as `Regions`.
+//! Each `Allocator` hands-off `Buffer`s from a single, large byte collection.
+//! `Buffer`s support bulk, volatile reads and writes.
+//!
+//! Every `Buffer` starts on a D-cache line, and the allocator pads it out
+//! to a whole number of lines. Cache maintenance works on whole lines, so
+//! this keeps maintenance on one `Buffer` from touching another `Buffer`'s
+//! memory.
 
+use crate::cache::CachePolicy;
 use core::{
     cell::UnsafeCell,
     sync::atomic::{AtomicBool, Ordering},
 };
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+
+/// The Cortex-M7 D-cache line size, in bytes.
+pub const CACHE_LINE_SIZE: usize = 32;
+
+/// Byte storage that starts on a D-cache line.
+#[repr(C, align(32))]
+struct CacheLines<const SIZE: usize>([u8; SIZE]);
 
 /// Memory for endpoint I/O.
 ///
 /// This allocates `SIZE` total bytes. The memory is then allocated for endpoints based
-/// on the need of each class.
+/// on the need of each class. Each endpoint buffer is padded to a whole number of
+/// 32 byte D-cache lines, so account for this padding when you choose `SIZE`.
 ///
 /// Allocate this in a static, and supply it to your driver. Construction panics if the
 /// endpoint memory has already been assigned to another USB driver.
@@ -22,9 +37,21 @@ use core::{
 ///
 /// static EP_MEMORY: EndpointMemory<4096> = EndpointMemory::new();
 /// ```
+///
+/// If the memory is non-cacheable, you can skip D-cache maintenance on
+/// endpoint buffers. See [`CachePolicy`](crate::CachePolicy) for more information.
+///
+/// ```
+/// use imxrt_usbd::{CachePolicy, EndpointMemory};
+///
+/// // TODO place in DTCM...
+/// static EP_MEMORY: EndpointMemory<4096> =
+///     EndpointMemory::new().with_cache_policy(CachePolicy::Skip);
+/// ```
 pub struct EndpointMemory<const SIZE: usize> {
-    buffer: UnsafeCell<[u8; SIZE]>,
+    buffer: UnsafeCell<CacheLines<SIZE>>,
     taken: AtomicBool,
+    policy: CachePolicy,
 }
 
 impl<const SIZE: usize> Default for EndpointMemory<SIZE> {
@@ -35,13 +62,21 @@ impl<const SIZE: usize> Default for EndpointMemory<SIZE> {
 
 impl<const SIZE: usize> EndpointMemory<SIZE> {
     /// Allocate endpoint memory.
+    ///
+    /// The memory uses the default [`CachePolicy`].
     pub const fn new() -> Self {
         Self {
-            buffer: UnsafeCell::new([0; SIZE]),
+            buffer: UnsafeCell::new(CacheLines([0; SIZE])),
             taken: AtomicBool::new(false),
+            policy: CachePolicy::DEFAULT,
         }
     }
 
+    /// Set the D-cache maintenance policy for endpoint buffers.
+    pub const fn with_cache_policy(self, policy: CachePolicy) -> Self {
+        Self { policy, ..self }
+    }
+
     /// Acquire the allocator for this endpoint memory.
     ///
     /// Returns `None` if the allocator has already been taken.
@@ -51,17 +86,59 @@ impl<const SIZE: usize> EndpointMemory<SIZE> {
         } else {
             // Safety: taken guards mutable access so that there's only one live
             // mutable static.
-            Some(Allocator::new(unsafe { &mut *self.buffer.get() }))
+            Some(Allocator::new(
+                unsafe { &mut (*self.buffer.get()).0 },
+                self.policy,
+            ))
         }
     }
 }
 
 unsafe impl<const SIZE: usize> Sync for EndpointMemory<SIZE> {}
 
+/// The number of freed blocks that an `Allocator` can track
+///
+/// Neighboring free blocks merge, so this is the number of discontiguous
+/// free blocks.
+const FREE_BLOCKS: usize = crate::state::MAX_ENDPOINTS;
+
+/// A block of freed memory
+#[derive(Clone, Copy, Default)]
+struct Block {
+    start: usize,
+    /// Zero if this block isn't tracking memory.
+    len: usize,
+}
+
+impl Block {
+    fn end(&self) -> usize {
+        self.start + self.len
+    }
+}
+
+/// Returns the highest address, between `start` and `end`, where a `padded` byte
+/// allocation with `align`ment fits
+fn place(start: usize, end: usize, padded: usize, align: usize) -> Option<usize> {
+    let ptr = end.checked_sub(padded)? & !(align - 1);
+    (ptr >= start).then_some(ptr)
+}
+
 /// Endpoint memory buffer allocator
+///
+/// The allocator hands out memory from the top of the collection, moving
+/// down. Freed buffers that sit directly above the unallocated memory are
+/// returned to the unallocated memory. Other freed buffers are kept in a
+/// small list of free blocks, and later allocations prefer these blocks.
 pub struct Allocator {
     start: *mut u8,
     ptr: *mut u8,
+    end: *mut u8,
+    free: [Block; FREE_BLOCKS],
+    /// Bytes reserved by allocated buffers.
+    used: usize,
+    /// The largest value of `used`.
+    high_water_mark: usize,
+    policy: CachePolicy,
 }
 
 // Safety: OK to send across execution contexts, because
@@ -70,13 +147,17 @@ unsafe impl Send for crate::buffer::Allocator {}
 
 impl Allocator {
     /// Create a memory allocator that allocates block from static, mutable memory.
-    fn new(buffer: &'static mut [u8]) -> Self {
+    pub(crate) fn new(buffer: &'static mut [u8], policy: CachePolicy) -> Self {
         // Safety: buffer is static.
-        unsafe { Self::from_buffer(buffer) }
+        let mut alloc = unsafe { Self::from_buffer(buffer) };
+        alloc.policy = policy;
+        alloc
     }
 
     /// Create an allocator for a non-static buffer.
     ///
+    /// Buffers always maintain the D-cache.
+    ///
     /// # Safety
     ///
     /// Caller must make sure that no buffers allocated from this object
@@ -84,25 +165,352 @@ impl Allocator {
     pub(crate) unsafe fn from_buffer(buffer: &mut [u8]) -> Self {
         let start = buffer.as_mut_ptr();
         let ptr = unsafe { start.add(buffer.len()) };
-        Allocator { start, ptr }
+        Allocator {
+            start,
+            ptr,
+            end: ptr,
+            free: [Block::default(); FREE_BLOCKS],
+            used: 0,
+            high_water_mark: 0,
+            policy: CachePolicy::Maintain,
+        }
     }
 
     /// Allocates a buffer of `size`
     ///
     /// The pointer returned from `allocate` is guaranteed to be at least `size`
-    /// bytes large.
+    /// bytes large. The buffer starts on a D-cache line, and no other buffer
+    /// shares its last line.
+    #[cfg_attr(not(test), allow(dead_code))]
     pub fn allocate(&mut self, size: usize) -> Option<Buffer> {
-        let ptr = self.ptr as usize;
-        let ptr = ptr.checked_sub(size)?;
-        let start = self.start as usize;
-        if ptr < start {
-            None
+        self.allocate_aligned(size, CACHE_LINE_SIZE)
+    }
+
+    /// Allocates a buffer of `size` that starts on an `align` boundary
+    ///
+    /// `align` must be a power of two. The buffer is always aligned to at least
+    /// a D-cache line, and it's padded to a whole number of lines. Memory skipped
+    /// to meet the alignment, or to pad the buffer, is wasted until the buffer
+    /// is freed.
+    pub fn allocate_aligned(&mut self, size: usize, align: usize) -> Option<Buffer> {
+        debug_assert!(align.is_power_of_two());
+        let align = align.max(CACHE_LINE_SIZE);
+        let padded = size.checked_next_multiple_of(CACHE_LINE_SIZE)?;
+
+        // Prefer free blocks, keeping the unallocated memory in one piece.
+        let from_block = self
+            .free
+            .iter_mut()
+            .filter(|block| block.len != 0)
+            .find_map(|block| {
+                let ptr = place(block.start, block.end(), padded, align)?;
+                let reserved = block.end() - ptr;
+                block.len = ptr - block.start;
+                Some((ptr, reserved))
+            });
+
+        let (ptr, reserved) = match from_block {
+            Some(allocation) => allocation,
+            None => {
+                let ptr = place(self.start as usize, self.ptr as usize, padded, align)?;
+                let reserved = self.ptr as usize - ptr;
+                self.ptr = ptr as *mut u8;
+                (ptr, reserved)
+            }
+        };
+
+        self.used += reserved;
+        self.high_water_mark = self.high_water_mark.max(self.used);
+        Some(Buffer {
+            ptr: ptr as *mut u8,
+            len: size,
+            reserved,
+            policy: self.policy,
+        })
+    }
+
+    /// Indicates if `buffer` is within this allocator's memory
+    pub fn contains(&self, buffer: &Buffer) -> bool {
+        (self.start as usize..self.end as usize).contains(&(buffer.ptr as usize))
+    }
+
+    /// Returns the total size of the memory, in bytes
+    pub fn total(&self) -> usize {
+        self.end as usize - self.start as usize
+    }
+
+    /// Returns the number of bytes reserved by allocated buffers
+    ///
+    /// This includes padding, and memory that couldn't be freed.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Returns the largest number of bytes that were ever in use
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    /// Return a buffer's memory to the allocator
+    ///
+    /// The memory can then be allocated again. Returns `false` if the allocator
+    /// can't track any more free blocks; the memory is lost. Freeing a buffer
+    /// that didn't come from this allocator, or a [`subrange`](Buffer::subrange),
+    /// does nothing and returns `false`.
+    ///
+    /// The caller must make sure that the USB controller isn't using the buffer.
+    pub fn free(&mut self, buffer: Buffer) -> bool {
+        let mut block = Block {
+            start: buffer.ptr as usize,
+            len: buffer.reserved,
+        };
+        if block.len == 0 || block.start < self.ptr as usize || block.end() > self.end as usize {
+            return false;
+        }
+
+        self.used -= block.len;
+        // Free blocks never neighbor each other, so there's at most one
+        // block on each side.
+        for free in self.free.iter_mut().filter(|free| free.len != 0) {
+            if free.end() == block.start {
+                block.start = free.start;
+                block.len += free.len;
+                free.len = 0;
+            } else if block.end() == free.start {
+                block.len += free.len;
+                free.len = 0;
+            }
+        }
+
+        if block.start == self.ptr as usize {
+            self.ptr = block.end() as *mut u8;
+            true
+        } else if let Some(free) = self.free.iter_mut().find(|free| free.len == 0) {
+            *free = block;
+            true
         } else {
-            self.ptr = ptr as *mut u8;
-            Some(Buffer {
-                ptr: self.ptr,
-                len: size,
-            })
+            self.used += buffer.reserved;
+            false
+        }
+    }
+}
+
+/// Selects the endpoints that use a region of endpoint memory
+///
+/// See [`BusAdapter::add_endpoint_memory`](crate::BusAdapter::add_endpoint_memory).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Placement {
+    /// Any endpoint.
+    Any,
+    /// Endpoints of this type.
+    ///
+    /// For isochronous endpoints, the synchronization and usage types
+    /// are ignored.
+    Kind(EndpointType),
+    /// This endpoint.
+    Endpoint(EndpointAddress),
+}
+
+impl Placement {
+    /// Returns the rank of this placement for an endpoint, or `None`
+    /// if the placement doesn't select the endpoint
+    ///
+    /// Lower ranks are more specific.
+    fn rank(self, addr: EndpointAddress, kind: EndpointType) -> Option<u8> {
+        match self {
+            Self::Endpoint(selected) => (selected == addr).then_some(0),
+            Self::Kind(selected) => {
+                (core::mem::discriminant(&selected) == core::mem::discriminant(&kind)).then_some(1)
+            }
+            Self::Any => Some(2),
+        }
+    }
+}
+
+/// The maximum number of endpoint memory regions.
+pub const MAX_REGIONS: usize = 4;
+
+/// A region of endpoint memory
+struct Region {
+    allocator: Allocator,
+    placement: Placement,
+}
+
+/// A collection of endpoint memory regions
+///
+/// Region zero is the endpoint memory supplied to the driver's constructor.
+/// It accepts any endpoint.
+pub struct Regions {
+    regions: [Option<Region>; MAX_REGIONS],
+}
+
+impl Regions {
+    /// Create regions with a single region that accepts any endpoint
+    pub fn new(allocator: Allocator) -> Self {
+        let mut regions = Regions {
+            regions: [const { None }; MAX_REGIONS],
+        };
+        regions.regions[0] = Some(Region {
+            allocator,
+            placement: Placement::Any,
+        });
+        regions
+    }
+
+    /// Indicates if there's room for another region
+    pub fn is_full(&self) -> bool {
+        self.regions.iter().all(Option::is_some)
+    }
+
+    /// Add a region, returning its index
+    ///
+    /// Returns `None` if there's no room for another region.
+    pub fn add(&mut self, allocator: Allocator, placement: Placement) -> Option<usize> {
+        let (index, region) = self
+            .regions
+            .iter_mut()
+            .enumerate()
+            .find(|(_, region)| region.is_none())?;
+        *region = Some(Region {
+            allocator,
+            placement,
+        });
+        Some(index)
+    }
+
+    /// Returns an iterator over all allocators
+    pub fn iter(&self) -> impl Iterator<Item = &Allocator> {
+        self.regions
+            .iter()
+            .flatten()
+            .map(|region| &region.allocator)
+    }
+
+    /// Returns the index of the region that holds `buffer`
+    pub fn region_of(&self, buffer: &Buffer) -> Option<usize> {
+        self.regions.iter().position(|region| {
+            region
+                .as_ref()
+                .is_some_and(|region| region.allocator.contains(buffer))
+        })
+    }
+
+    /// Allocate a buffer for an endpoint, aligned to `align`
+    ///
+    /// Tries each region that selects the endpoint, from the most specific
+    /// placement to the least specific placement. Regions with the same
+    /// placement are tried in the order they were added.
+    pub fn allocate(
+        &mut self,
+        addr: EndpointAddress,
+        kind: EndpointType,
+        size: usize,
+        align: usize,
+    ) -> Option<Buffer> {
+        (0..=2).find_map(|rank| {
+            self.regions
+                .iter_mut()
+                .flatten()
+                .filter(|region| region.placement.rank(addr, kind) == Some(rank))
+                .find_map(|region| region.allocator.allocate_aligned(size, align))
+        })
+    }
+
+    /// Return a buffer's memory to its region
+    ///
+    /// See [`Allocator::free`].
+    pub fn free(&mut self, buffer: Buffer) -> bool {
+        self.regions
+            .iter_mut()
+            .flatten()
+            .find(|region| region.allocator.contains(&buffer))
+            .is_some_and(|region| region.allocator.free(buffer))
+    }
+}
+
+/// The size of a word-sized volatile access.
+const WORD_SIZE: usize = core::mem::size_of::<u32>();
+
+/// Returns the number of bytes from `ptr` to the next word boundary
+fn bytes_to_word(ptr: *const u8) -> usize {
+    (WORD_SIZE - ptr as usize % WORD_SIZE) % WORD_SIZE
+}
+
+/// Copy `dst.len()` bytes from `src` into `dst`
+///
+/// Every read from `src` is volatile. Once `src` reaches a word boundary,
+/// the reads are word sized. `dst` may have any alignment.
+///
+/// # Safety
+///
+/// `src` must be valid for reads of `dst.len()` bytes.
+unsafe fn volatile_copy_from(src: *const u8, dst: &mut [u8]) {
+    let (head, body) = dst.split_at_mut(bytes_to_word(src).min(dst.len()));
+    let mut src = src;
+    for byte in head.iter_mut() {
+        // Safety: caller ensures that src is valid for dst.len() bytes.
+        unsafe {
+            *byte = src.read_volatile();
+            src = src.add(1);
+        }
+    }
+
+    let mut words = body.chunks_exact_mut(WORD_SIZE);
+    for word in &mut words {
+        // Safety: src is on a word boundary, and it's valid for at least
+        // one more word.
+        unsafe {
+            let value = src.cast::<u32>().read_volatile();
+            word.copy_from_slice(&value.to_ne_bytes());
+            src = src.add(WORD_SIZE);
+        }
+    }
+
+    for byte in words.into_remainder() {
+        // Safety: caller ensures that src is valid for dst.len() bytes.
+        unsafe {
+            *byte = src.read_volatile();
+            src = src.add(1);
+        }
+    }
+}
+
+/// Copy `src` into the `src.len()` bytes at `dst`
+///
+/// Every write to `dst` is volatile. Once `dst` reaches a word boundary,
+/// the writes are word sized. `src` may have any alignment.
+///
+/// # Safety
+///
+/// `dst` must be valid for writes of `src.len()` bytes.
+unsafe fn volatile_copy_into(dst: *mut u8, src: &[u8]) {
+    let (head, body) = src.split_at(bytes_to_word(dst).min(src.len()));
+    let mut dst = dst;
+    for byte in head {
+        // Safety: caller ensures that dst is valid for src.len() bytes.
+        unsafe {
+            dst.write_volatile(*byte);
+            dst = dst.add(1);
+        }
+    }
+
+    let words = body.chunks_exact(WORD_SIZE);
+    let tail = words.remainder();
+    for word in words {
+        // Safety: dst is on a word boundary, and it's valid for at least
+        // one more word. The chunk is exactly one word.
+        unsafe {
+            let value = u32::from_ne_bytes(word.try_into().unwrap_unchecked());
+            dst.cast::<u32>().write_volatile(value);
+            dst = dst.add(WORD_SIZE);
+        }
+    }
+
+    for byte in tail {
+        // Safety: caller ensures that dst is valid for src.len() bytes.
+        unsafe {
+            dst.write_volatile(*byte);
+            dst = dst.add(1);
         }
     }
 }
@@ -111,6 +519,10 @@ impl Allocator {
 pub struct Buffer {
     ptr: *mut u8,
     len: usize,
+    /// The memory reserved for this buffer in its allocator, including
+    /// padding. Zero for a subrange.
+    reserved: usize,
+    policy: CachePolicy,
 }
 
 // Safety: OK to send `Buffer` across execution contexts. It's
@@ -121,36 +533,41 @@ impl Buffer {
     /// Read the contents of this buffer into `buffer`, returning
     /// how many elements were read
     ///
-    /// All reads from this buffer are volatile.
+    /// All reads from this buffer are volatile. Reads are word sized, except
+    /// for the bytes before the first word boundary, and after the last word
+    /// boundary.
     pub fn volatile_read(&self, buffer: &mut [u8]) -> usize {
         let size = buffer.len().min(self.len);
-        buffer
-            .iter_mut()
-            .take(size)
-            .fold(self.ptr, |src, dst| unsafe {
-                // Safety: pointer valid for `len` elements, the take() prevents
-                // us from going out of bounds.
-                *dst = src.read_volatile();
-                src.add(1)
-            });
+        // Safety: pointer valid for `len` elements, and size doesn't exceed len.
+        unsafe { volatile_copy_from(self.ptr, &mut buffer[..size]) };
         size
     }
 
     /// Write the contents from `buffer` into this memory buffer,
     /// returning how many elements were written
     ///
-    /// All writes into this buffer are volatile.
+    /// All writes into this buffer are volatile. Writes are word sized, except
+    /// for the bytes before the first word boundary, and after the last word
+    /// boundary.
     pub fn volatile_write(&mut self, buffer: &[u8]) -> usize {
         let size = buffer.len().min(self.len);
-        buffer.iter().take(size).fold(self.ptr, |dst, src| unsafe {
-            // Safety: pointer valid for `len` elements, the take() prevents
-            // us from going out of bounds.
-            dst.write_volatile(*src);
-            dst.add(1)
-        });
+        // Safety: pointer valid for `len` elements, and size doesn't exceed len.
+        unsafe { volatile_copy_into(self.ptr, &buffer[..size]) };
         size
     }
 
+    /// Returns the start of this memory buffer
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr
+    }
+
+    /// Returns the number of bytes reserved for this buffer, including padding
+    ///
+    /// Returns zero for a [`subrange`](Buffer::subrange).
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
     /// Returns the start of this memory buffer
     pub fn as_ptr_mut(&mut self) -> *mut u8 {
         self.ptr
@@ -161,25 +578,87 @@ impl Buffer {
         self.len
     }
 
-    /// Clean and invalidate at least `len` buffer from DCache
+    /// Returns a buffer that describes at most `len` bytes of this buffer,
+    /// starting at `offset`
+    ///
+    /// The returned buffer is clamped to the end of this buffer.
+    ///
+    /// # Safety
     ///
-    /// Cleans at most `len()` bytes.
-    pub fn clean_invalidate_dcache(&self, len: usize) {
-        crate::cache::clean_invalidate_dcache_by_address(self.ptr as usize, self.len.min(len));
+    /// The returned buffer aliases this buffer. Caller must make sure that
+    /// the two buffers are not used to access the same memory at the same time.
+    pub unsafe fn subrange(&self, offset: usize, len: usize) -> Buffer {
+        let offset = offset.min(self.len);
+        Buffer {
+            // Safety: offset is within the buffer, or one past the end.
+            ptr: unsafe { self.ptr.add(offset) },
+            len: len.min(self.len - offset),
+            reserved: 0,
+            policy: self.policy,
+        }
+    }
+
+    /// Clean at least `len` bytes of this buffer from DCache
+    ///
+    /// Cleans at most `len()` bytes. Use this before the controller reads
+    /// the buffer.
+    pub fn clean_dcache(&self, len: usize) {
+        self.policy
+            .clean_dcache_by_address(self.ptr as usize, self.len.min(len));
+    }
+
+    /// Invalidate at least `len` bytes of this buffer from DCache
+    ///
+    /// Invalidates at most `len()` bytes. Use this before the CPU reads data
+    /// that the controller wrote into the buffer. Any CPU writes in the
+    /// invalidated cache lines are lost.
+    pub fn invalidate_dcache(&self, len: usize) {
+        self.policy
+            .invalidate_dcache_by_address(self.ptr as usize, self.len.min(len));
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Allocator;
+    extern crate std;
+    use std::vec::Vec;
+
+    use super::{
+        Allocator, Buffer, CACHE_LINE_SIZE, CacheLines, CachePolicy, FREE_BLOCKS, WORD_SIZE,
+    };
+
+    /// Returns the index of the first and last cache line touched by `buffer`.
+    fn lines(buffer: &Buffer) -> (usize, usize) {
+        let start = buffer.ptr as usize;
+        let end = start + buffer.len() - 1;
+        (start / CACHE_LINE_SIZE, end / CACHE_LINE_SIZE)
+    }
+
+    /// Asserts that no two buffers touch the same cache line.
+    ///
+    /// Empty buffers don't touch any memory.
+    fn assert_no_shared_lines(buffers: &[Buffer]) {
+        let mut buffers = buffers.iter().filter(|buffer| buffer.len() > 0);
+        while let Some(buffer) = buffers.next() {
+            assert_eq!(buffer.ptr as usize % CACHE_LINE_SIZE, 0);
+            let (first, last) = lines(buffer);
+            for other in buffers.clone() {
+                let (other_first, other_last) = lines(other);
+                assert!(
+                    last < other_first || other_last < first,
+                    "buffers share a cache line"
+                );
+            }
+        }
+    }
 
     #[test]
     fn allocate_entire_buffer() {
-        let mut buffer: [u8; 32] = [0; 32];
-        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer) };
+        let mut buffer = CacheLines([0; 32]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
         let ptr = alloc.allocate(32);
         assert!(ptr.is_some());
-        assert_eq!(ptr.unwrap().ptr, buffer.as_mut_ptr());
+        assert_eq!(ptr.unwrap().ptr, buffer.0.as_mut_ptr());
 
         let ptr = alloc.allocate(1);
         assert!(ptr.is_none());
@@ -187,21 +666,444 @@ mod test {
 
     #[test]
     fn allocate_partial_buffers() {
-        let mut buffer: [u8; 32] = [0; 32];
-        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer) };
+        let mut buffer = CacheLines([0; 96]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
 
-        let ptr = alloc.allocate(7);
-        assert!(ptr.is_some());
-        assert_eq!(ptr.unwrap().ptr, unsafe { buffer.as_mut_ptr().add(32 - 7) });
+        let ptr = alloc.allocate(7).unwrap();
+        assert_eq!(ptr.ptr, unsafe { buffer.0.as_mut_ptr().add(64) });
+        assert_eq!(ptr.len(), 7);
+
+        let ptr = alloc.allocate(7).unwrap();
+        assert_eq!(ptr.ptr, unsafe { buffer.0.as_mut_ptr().add(32) });
+        assert_eq!(ptr.len(), 7);
+
+        let ptr = alloc.allocate(33);
+        assert!(ptr.is_none());
+
+        let ptr = alloc.allocate(32).unwrap();
+        assert_eq!(ptr.ptr, buffer.0.as_mut_ptr());
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        #[repr(align(64))]
+        struct Aligned([u8; 128]);
+        let mut buffer = Aligned([0; 128]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
 
         let ptr = alloc.allocate(7);
-        assert!(ptr.is_some());
         assert_eq!(ptr.unwrap().ptr, unsafe {
-            buffer.as_mut_ptr().add(32 - 14)
+            buffer.0.as_mut_ptr().add(128 - 32)
         });
 
-        let ptr = alloc.allocate(19);
-        assert!(ptr.is_none());
+        let ptr = alloc.allocate_aligned(7, 64).unwrap();
+        assert_eq!(ptr.ptr, unsafe { buffer.0.as_mut_ptr().add(64) });
+        assert_eq!(ptr.len(), 7);
+
+        let ptr = alloc.allocate_aligned(7, 64).unwrap();
+        assert_eq!(ptr.ptr, buffer.0.as_mut_ptr());
+
+        assert!(alloc.allocate_aligned(1, 64).is_none());
+    }
+
+    #[test]
+    fn allocate_unaligned_memory() {
+        let mut buffer = CacheLines([0; 128]);
+        // Memory that starts and ends in the middle of a cache line.
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0[5..123]) };
+
+        let ptr = alloc.allocate(1).unwrap();
+        assert_eq!(ptr.ptr, unsafe { buffer.0.as_mut_ptr().add(64) });
+        let ptr = alloc.allocate(1).unwrap();
+        assert_eq!(ptr.ptr, unsafe { buffer.0.as_mut_ptr().add(32) });
+        assert!(alloc.allocate(1).is_none());
+    }
+
+    #[test]
+    fn buffers_never_share_cache_lines() {
+        let mut buffer = CacheLines([0; 4096]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0[3..]) };
+
+        let mut buffers = Vec::new();
+        for size in [1, 7, 31, 32, 33, 64, 0, 65, 100, 512, 3, 1000] {
+            buffers.push(alloc.allocate(size).unwrap());
+        }
+        for (size, align) in [(7, 64), (96, 128), (1, 4), (200, 256)] {
+            buffers.push(alloc.allocate_aligned(size, align).unwrap());
+        }
+        assert_no_shared_lines(&buffers);
+
+        // Exhaust the memory with small buffers.
+        while let Some(buffer) = alloc.allocate(5) {
+            buffers.push(buffer);
+        }
+        assert_no_shared_lines(&buffers);
+    }
+
+    #[test]
+    fn subrange() {
+        let mut buffer = CacheLines([0; 32]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+        let buf = alloc.allocate(16).unwrap();
+
+        let sub = unsafe { buf.subrange(4, 8) };
+        assert_eq!(sub.ptr, unsafe { buf.ptr.add(4) });
+        assert_eq!(sub.len(), 8);
+
+        let sub = unsafe { buf.subrange(12, 8) };
+        assert_eq!(sub.len(), 4);
+
+        let sub = unsafe { buf.subrange(20, 8) };
+        assert_eq!(sub.ptr, unsafe { buf.ptr.add(16) });
+        assert_eq!(sub.len(), 0);
+    }
+
+    #[test]
+    fn dcache_maintenance() {
+        use crate::cache::{Operation, recorder};
+
+        let mut buffer = CacheLines([0; 64]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+        let buf = alloc.allocate(40).unwrap();
+        let addr = buf.ptr as usize;
+
+        recorder::take();
+        buf.clean_dcache(16);
+        buf.invalidate_dcache(64);
+        buf.clean_dcache(0);
+        assert_eq!(
+            recorder::take(),
+            [
+                (Operation::Clean, addr, 16),
+                (Operation::Invalidate, addr, 40)
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_dcache_maintenance() {
+        use crate::cache::recorder;
+
+        let mut buffer = CacheLines([0; 64]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+        alloc.policy = CachePolicy::Skip;
+        let buf = alloc.allocate(40).unwrap();
+
+        recorder::take();
+        buf.clean_dcache(16);
+        unsafe { buf.subrange(0, 32) }.invalidate_dcache(32);
+        assert!(recorder::take().is_empty());
+    }
+
+    #[test]
+    fn free_most_recent() {
+        let mut buffer = CacheLines([0; 96]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+
+        let first = alloc.allocate(7).unwrap();
+        let second = alloc.allocate(40).unwrap();
+        assert!(alloc.allocate(1).is_none());
+
+        assert!(alloc.free(second));
+        let second = alloc.allocate(64).unwrap();
+        assert_eq!(second.ptr, buffer.0.as_mut_ptr());
+
+        // Returns all memory to the allocator.
+        assert!(alloc.free(second));
+        assert!(alloc.free(first));
+        assert_eq!(alloc.ptr, alloc.end);
+        assert!(alloc.allocate(96).is_some());
+    }
+
+    #[test]
+    fn reuse_freed_blocks() {
+        let mut buffer = CacheLines([0; 128]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+
+        let first = alloc.allocate(32).unwrap();
+        let second = alloc.allocate(33).unwrap();
+        let third = alloc.allocate(1).unwrap();
+        assert!(alloc.allocate(1).is_none());
+
+        // Free a buffer in the middle, then allocate a smaller buffer.
+        let second_ptr = second.ptr;
+        assert!(alloc.free(second));
+        let small = alloc.allocate(20).unwrap();
+        assert_eq!(small.ptr, unsafe { second_ptr.add(32) });
+        let small2 = alloc.allocate(20).unwrap();
+        assert_eq!(small2.ptr, second_ptr);
+        assert!(alloc.allocate(1).is_none());
+
+        // Neighboring free blocks merge, and return to the unallocated memory.
+        assert!(alloc.free(small));
+        assert!(alloc.free(first));
+        assert!(alloc.free(small2));
+        assert_eq!(alloc.ptr, third.ptr);
+        assert!(alloc.free(third));
+        assert_eq!(alloc.ptr, alloc.end);
+        assert!(alloc.allocate(128).is_some());
+    }
+
+    #[test]
+    fn free_aligned() {
+        #[repr(align(64))]
+        struct Aligned([u8; 128]);
+        let mut buffer = Aligned([0; 128]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+
+        let first = alloc.allocate(7).unwrap();
+        let aligned = alloc.allocate_aligned(40, 64).unwrap();
+        assert_eq!(aligned.ptr, buffer.0.as_mut_ptr());
+        assert!(alloc.free(first));
+        // The padding above the aligned buffer is reclaimed, too.
+        assert!(alloc.free(aligned));
+        assert!(alloc.allocate(128).is_some());
+    }
+
+    #[test]
+    fn free_foreign_buffers() {
+        let mut buffer = CacheLines([0; 64]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+        let mut other = CacheLines([0; 64]);
+        let mut other_alloc = unsafe { Allocator::from_buffer(&mut other.0) };
+
+        let buf = alloc.allocate(64).unwrap();
+        assert!(!alloc.free(unsafe { buf.subrange(0, 32) }));
+        assert!(!alloc.free(other_alloc.allocate(32).unwrap()));
+        assert!(alloc.allocate(1).is_none());
+    }
+
+    #[test]
+    fn usage() {
+        #[repr(align(256))]
+        struct Aligned([u8; 256]);
+        let mut buffer = Aligned([0; 256]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+        assert_eq!(alloc.total(), 256);
+        assert_eq!(alloc.used(), 0);
+        assert_eq!(alloc.high_water_mark(), 0);
+
+        let first = alloc.allocate(7).unwrap();
+        assert_eq!(first.reserved(), 32);
+        let second = alloc.allocate(33).unwrap();
+        assert_eq!(second.reserved(), 64);
+        assert_eq!(alloc.used(), 96);
+
+        assert!(alloc.free(first));
+        assert_eq!(alloc.used(), 64);
+        assert_eq!(alloc.high_water_mark(), 96);
+
+        // Includes the padding skipped for alignment.
+        let aligned = alloc.allocate_aligned(1, 256).unwrap();
+        assert_eq!(aligned.reserved(), 160);
+        assert_eq!(alloc.used(), 224);
+        assert_eq!(alloc.high_water_mark(), 224);
+
+        assert!(alloc.free(second));
+        assert!(alloc.free(aligned));
+        assert_eq!(alloc.used(), 0);
+        assert_eq!(alloc.high_water_mark(), 224);
+    }
+
+    #[test]
+    fn free_list_full() {
+        let mut buffer = CacheLines([0; 2048]);
+        let mut alloc = unsafe { Allocator::from_buffer(&mut buffer.0) };
+
+        let buffers: Vec<_> = core::iter::from_fn(|| alloc.allocate(1)).collect();
+        assert_eq!(buffers.len(), 64);
+
+        // Free every other buffer, so that no free blocks merge.
+        let freed: Vec<_> = buffers
+            .into_iter()
+            .step_by(2)
+            .take(FREE_BLOCKS + 1)
+            .map(|buffer| alloc.free(buffer))
+            .collect();
+        assert!(freed[..FREE_BLOCKS].iter().all(|&freed| freed));
+        assert!(!freed[FREE_BLOCKS]);
+        // Memory that couldn't be freed is still in use.
+        assert_eq!(alloc.used(), 2048 - FREE_BLOCKS * 32);
+    }
+
+    #[test]
+    fn region_placement() {
+        use super::{Placement, Regions};
+        use usb_device::endpoint::{
+            EndpointAddress, EndpointType, IsochronousSynchronizationType, IsochronousUsageType,
+        };
+
+        let mut any = CacheLines([0; 128]);
+        let mut iso = CacheLines([0; 128]);
+        let mut ep = CacheLines([0; 128]);
+        let mut regions = Regions::new(unsafe { Allocator::from_buffer(&mut any.0) });
+        assert_eq!(
+            regions.add(
+                unsafe { Allocator::from_buffer(&mut iso.0) },
+                Placement::Kind(EndpointType::Isochronous {
+                    synchronization: IsochronousSynchronizationType::Asynchronous,
+                    usage: IsochronousUsageType::Data,
+                }),
+            ),
+            Some(1)
+        );
+        let addr = EndpointAddress::from(0x82);
+        assert_eq!(
+            regions.add(
+                unsafe { Allocator::from_buffer(&mut ep.0) },
+                Placement::Endpoint(addr),
+            ),
+            Some(2)
+        );
+
+        let iso_type = EndpointType::Isochronous {
+            synchronization: IsochronousSynchronizationType::NoSynchronization,
+            usage: IsochronousUsageType::Feedback,
+        };
+        let other = EndpointAddress::from(0x81);
+
+        let buf = regions.allocate(other, iso_type, 64, 32).unwrap();
+        assert_eq!(regions.region_of(&buf), Some(1));
+        let buf = regions.allocate(addr, iso_type, 64, 32).unwrap();
+        assert_eq!(regions.region_of(&buf), Some(2));
+        let buf = regions.allocate(other, EndpointType::Bulk, 64, 32).unwrap();
+        assert_eq!(regions.region_of(&buf), Some(0));
+
+        // Bulk endpoints don't use the isochronous region.
+        assert!(
+            regions
+                .allocate(other, EndpointType::Bulk, 128, 32)
+                .is_none()
+        );
+
+        // Falls back to less specific regions.
+        let buf = regions.allocate(addr, iso_type, 64, 32).unwrap();
+        assert_eq!(regions.region_of(&buf), Some(2));
+        let buf = regions.allocate(addr, iso_type, 64, 32).unwrap();
+        assert_eq!(regions.region_of(&buf), Some(1));
+        let last = regions.allocate(addr, iso_type, 64, 32).unwrap();
+        assert_eq!(regions.region_of(&last), Some(0));
+        assert!(regions.allocate(addr, iso_type, 32, 32).is_none());
+
+        // Frees into the owning region.
+        assert!(regions.free(buf));
+        assert_eq!(regions.iter().nth(1).unwrap().used(), 64);
+        assert_eq!(
+            regions.iter().map(Allocator::used).sum::<usize>(),
+            128 * 3 - 64
+        );
+        let buf = regions.allocate(other, iso_type, 64, 32).unwrap();
+        assert_eq!(regions.region_of(&buf), Some(1));
+    }
+
+    /// A recognizable byte pattern, distinct from zero.
+    fn pattern(len: usize) -> impl Iterator<Item = u8> {
+        (0..len).map(|idx| (idx as u8).wrapping_mul(7).wrapping_add(1))
+    }
+
+    #[test]
+    fn volatile_read_alignments() {
+        let mut memory = CacheLines([0; 64]);
+        memory
+            .0
+            .iter_mut()
+            .zip(pattern(64))
+            .for_each(|(m, p)| *m = p);
+        let buffer = unsafe { Allocator::from_buffer(&mut memory.0) }
+            .allocate(64)
+            .unwrap();
+
+        for src_offset in 0..2 * WORD_SIZE {
+            for dst_offset in 0..2 * WORD_SIZE {
+                for len in 0..=40 {
+                    let src = unsafe { buffer.subrange(src_offset, len) };
+                    let mut dst = [0; 64];
+                    let end = dst_offset + len;
+                    assert_eq!(src.volatile_read(&mut dst[dst_offset..end]), len);
+
+                    let expected: Vec<u8> = pattern(64).skip(src_offset).take(len).collect();
+                    assert_eq!(dst[dst_offset..end], expected);
+                    assert!(dst[..dst_offset].iter().all(|&b| b == 0));
+                    assert!(dst[end..].iter().all(|&b| b == 0));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn volatile_write_alignments() {
+        let mut memory = CacheLines([0; 64]);
+        let mut buffer = unsafe { Allocator::from_buffer(&mut memory.0) }
+            .allocate(64)
+            .unwrap();
+        let src: Vec<u8> = pattern(64).collect();
+
+        for dst_offset in 0..2 * WORD_SIZE {
+            for src_offset in 0..2 * WORD_SIZE {
+                for len in 0..=40 {
+                    unsafe { core::ptr::write_bytes(buffer.as_ptr_mut(), 0, 64) };
+                    let mut dst = unsafe { buffer.subrange(dst_offset, len) };
+                    let end = dst_offset + len;
+                    assert_eq!(dst.volatile_write(&src[src_offset..src_offset + len]), len);
+
+                    let memory = unsafe { core::slice::from_raw_parts(buffer.as_ptr(), 64) };
+                    assert_eq!(memory[dst_offset..end], src[src_offset..src_offset + len]);
+                    assert!(memory[..dst_offset].iter().all(|&b| b == 0));
+                    assert!(memory[end..].iter().all(|&b| b == 0));
+                }
+            }
+        }
+    }
+
+    /// Word-sized copies are faster than byte-sized copies
+    ///
+    /// Timing depends on the host, so this doesn't run by default. Run it
+    /// with `cargo test --release -- --ignored volatile_copy_benchmark`.
+    #[test]
+    #[ignore]
+    fn volatile_copy_benchmark() {
+        use std::time::{Duration, Instant};
+
+        const LEN: usize = 512;
+        const ITERATIONS: u32 = 1000;
+
+        let mut memory = CacheLines([0; LEN]);
+        let mut buffer = unsafe { Allocator::from_buffer(&mut memory.0) }
+            .allocate(LEN)
+            .unwrap();
+        let src: Vec<u8> = pattern(LEN).collect();
+        let mut dst = [0; LEN];
+
+        let time = |copy: &mut dyn FnMut()| -> Duration {
+            let start = Instant::now();
+            for _ in 0..ITERATIONS {
+                copy();
+            }
+            start.elapsed()
+        };
+
+        let words = time(&mut || {
+            buffer.volatile_write(&src);
+            buffer.volatile_read(&mut dst);
+        });
+        assert_eq!(dst[..], src[..]);
+
+        dst.fill(0);
+        let ptr = buffer.as_ptr_mut();
+        let bytes = time(&mut || {
+            for (idx, byte) in src.iter().enumerate() {
+                unsafe { ptr.add(idx).write_volatile(*byte) };
+            }
+            for (idx, byte) in dst.iter_mut().enumerate() {
+                *byte = unsafe { ptr.add(idx).read_volatile() };
+            }
+        });
+        assert_eq!(dst[..], src[..]);
+
+        assert!(
+            words < bytes,
+            "word copies took {words:?}, byte copies took {bytes:?}"
+        );
     }
 
     #[test]
@@ -209,6 +1111,11 @@ mod test {
         let mut alloc = Allocator {
             start: core::ptr::null_mut(),
             ptr: core::ptr::null_mut(),
+            end: core::ptr::null_mut(),
+            free: Default::default(),
+            used: 0,
+            high_water_mark: 0,
+            policy: CachePolicy::Maintain,
         };
         assert!(alloc.allocate(1).is_none());
     }
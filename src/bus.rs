@@ -10,7 +10,7 @@
 //! Most of the interesting behavior happens in the driver.
 
 use super::driver::Driver;
-use crate::gpt;
+use crate::{CachePolicy, Placement, gpt};
 use core::cell::RefCell;
 use cortex_m::interrupt::{self, Mutex};
 use usb_device::{
@@ -19,7 +19,10 @@ use usb_device::{
     endpoint::{EndpointAddress, EndpointType},
 };
 
-pub use super::driver::Speed;
+pub use super::driver::{
+    BusSpeed, EndpointStatus, EndpointUsage, ErrorCounts, Frame, IsochronousError, MemoryUsage,
+    NakEvents, Speed, TransferError, VbusEvent,
+};
 
 /// A full- and high-speed `UsbBus` implementation
 ///
@@ -96,7 +99,7 @@ pub use super::driver::Speed;
 /// ## Packets and transfers
 ///
 /// All i.MX RT USB drivers manage queue heads (QH), and transfer
-/// descriptors (TD). For the driver, each (QH) is assigned
+/// descriptors (TD). By default, each (QH) is assigned
 /// only one (TD) to perform I/O. We then assume each TD describes a single
 /// packet. This is simple to implement, but it means that the
 /// driver can only have one packet in flight per endpoint. You're expected
@@ -104,11 +107,68 @@ pub use super::driver::Speed;
 /// in the time required for devices. This becomes more important as you
 /// increase driver speeds.
 ///
+/// To keep more packets in flight, give each endpoint a pool of TDs with
+/// the `TD_COUNT` parameter of [`EndpointState`](crate::EndpointState).
+/// Writes queue packets on free TDs, and OUT endpoints are primed with
+/// every TD. The driver adds TDs to an endpoint's primed list while the
+/// controller is running, and retires completed TDs in order.
+///
+/// A TD can also describe a transfer of more than one packet. See
+/// [`set_max_transfer_len`](BusAdapter::set_max_transfer_len) to use transfers
+/// of up to 20 KiB.
+///
 /// The hardware can zero-length terminate (ZLT) packets as needed if you
 /// call [`enable_zlt`](BusAdapter::enable_zlt). By default, this feature is
 /// off, because most `usb-device` classes / devices take care to send zero-length
 /// packets, and enabling this feature could interfere with the class / device
 /// behaviors.
+///
+/// ## Endpoint memory
+///
+/// Endpoints take their buffers from [`EndpointMemory`](crate::EndpointMemory)
+/// when your classes allocate them. To change an endpoint's buffer, like when
+/// an alternate setting uses a different max packet size, use
+/// [`reallocate_endpoint`](BusAdapter::reallocate_endpoint). The driver reuses
+/// freed memory for later allocations.
+///
+/// You can spread endpoint memory across several regions, like OCRAM for
+/// isochronous endpoints and external SDRAM for large bulk transfers. Add
+/// regions with [`add_endpoint_memory`](BusAdapter::add_endpoint_memory) or
+/// [`add_endpoint_region`](BusAdapter::add_endpoint_region) before your classes
+/// allocate endpoints. A [`Placement`] selects the endpoints that use a region.
+/// The driver tries the most specific region first, and falls back to less
+/// specific regions when a region is full. The memory supplied to the constructor
+/// accepts any endpoint.
+///
+/// ## Isochronous endpoints
+///
+/// Each isochronous transfer moves one (micro)frame of data. At high speed,
+/// an isochronous endpoint can move up to three packets per microframe; encode
+/// the additional transactions in bits 12:11 of the max packet size, just like
+/// `wMaxPacketSize`. Give isochronous endpoints more than one TD so that the
+/// controller always has the next frame's transfer. Isochronous transfers
+/// ignore [`set_max_transfer_len`](BusAdapter::set_max_transfer_len).
+///
+/// The driver doesn't prime an idle isochronous endpoint right away, since the
+/// first transfer could then move in either the current (micro)frame or the next.
+/// Instead, the driver enables SOF interrupts, and `poll()` primes the endpoint
+/// just after the next (micro)frame boundary. The first transfer moves in the
+/// (micro)frame after that boundary; see [`isochronous_start_frame`](BusAdapter::isochronous_start_frame).
+/// Transfers scheduled while the endpoint streams don't wait.
+///
+/// A failed isochronous transfer doesn't halt the endpoint. The driver drops
+/// the transfer; see [`take_isochronous_error`](BusAdapter::take_isochronous_error)
+/// to learn about missed frames, and data buffer errors.
+///
+/// ## Suspend and resume
+///
+/// `poll()` reports a suspend once the controller detects an idle bus, and
+/// reports a resume when the host resumes the bus. When `usb-device` suspends
+/// the bus, the driver stops the PHY clock and powers down the PHY, so that a
+/// bus-powered device can meet the USB suspend current limit. A resume or reset
+/// on the bus powers the PHY back on, and the driver restores the PHY when
+/// `usb-device` resumes the bus. To wake the host, see
+/// [`remote_wakeup`](BusAdapter::remote_wakeup).
 pub struct BusAdapter {
     usb: Mutex<RefCell<Driver>>,
     cs: Option<cortex_m::interrupt::CriticalSection>,
@@ -123,10 +183,10 @@ impl BusAdapter {
     /// # Panics
     ///
     /// Panics if `buffer` or `state` has already been associated with another USB bus.
-    pub fn new<const N: u8, const SIZE: usize, const EP_COUNT: usize>(
+    pub fn new<const N: u8, const SIZE: usize, const EP_COUNT: usize, const TD_COUNT: usize>(
         instances: crate::Instances<N>,
         buffer: &'static crate::buffer::EndpointMemory<SIZE>,
-        state: &'static crate::state::EndpointState<EP_COUNT>,
+        state: &'static crate::state::EndpointState<EP_COUNT, TD_COUNT>,
     ) -> Self {
         Self::with_speed(instances, buffer, state, Speed::High)
     }
@@ -144,10 +204,15 @@ impl BusAdapter {
     /// # Panics
     ///
     /// Panics if `buffer` or `state` has already been associated with another USB bus.
-    pub fn with_speed<const N: u8, const SIZE: usize, const EP_COUNT: usize>(
+    pub fn with_speed<
+        const N: u8,
+        const SIZE: usize,
+        const EP_COUNT: usize,
+        const TD_COUNT: usize,
+    >(
         instances: crate::Instances<N>,
         buffer: &'static crate::buffer::EndpointMemory<SIZE>,
-        state: &'static crate::state::EndpointState<EP_COUNT>,
+        state: &'static crate::state::EndpointState<EP_COUNT, TD_COUNT>,
         speed: Speed,
     ) -> Self {
         Self::init(instances, buffer, state, speed, None)
@@ -171,10 +236,11 @@ impl BusAdapter {
         const N: u8,
         const SIZE: usize,
         const EP_COUNT: usize,
+        const TD_COUNT: usize,
     >(
         instances: crate::Instances<N>,
         buffer: &'static crate::buffer::EndpointMemory<SIZE>,
-        state: &'static crate::state::EndpointState<EP_COUNT>,
+        state: &'static crate::state::EndpointState<EP_COUNT, TD_COUNT>,
         speed: Speed,
     ) -> Self {
         Self::init(
@@ -188,10 +254,10 @@ impl BusAdapter {
         )
     }
 
-    fn init<const N: u8, const SIZE: usize, const EP_COUNT: usize>(
+    fn init<const N: u8, const SIZE: usize, const EP_COUNT: usize, const TD_COUNT: usize>(
         instances: crate::Instances<N>,
         buffer: &'static crate::buffer::EndpointMemory<SIZE>,
-        state: &'static crate::state::EndpointState<EP_COUNT>,
+        state: &'static crate::state::EndpointState<EP_COUNT, TD_COUNT>,
         speed: Speed,
         cs: Option<cortex_m::interrupt::CriticalSection>,
     ) -> Self {
@@ -225,6 +291,201 @@ impl BusAdapter {
         self.with_usb_mut(|usb| usb.enable_zlt(ep_addr));
     }
 
+    /// Set the maximum transfer length for an endpoint
+    ///
+    /// By default, each transfer moves at most one packet. Use this method to
+    /// let a single transfer move up to `max_transfer_len` bytes. The controller
+    /// splits a large IN transfer into packets. A large OUT transfer completes when
+    /// the buffer is full, or when the host sends a short packet. Either way, your
+    /// class handles one `poll()` event per transfer, not per packet.
+    ///
+    /// Once set, [`write`](UsbBus::write) and [`read`](UsbBus::read) on this endpoint
+    /// accept buffers up to the transfer length. The driver rounds the transfer length
+    /// down to a multiple of the endpoint's max packet size, and clamps it to 20 KiB.
+    /// Use [`max_transfer_len`](BusAdapter::max_transfer_len) to query the transfer
+    /// length after the endpoint is allocated.
+    ///
+    /// You must call this before your class allocates the endpoint, since the driver
+    /// sizes the endpoint's memory during allocation. Transfers longer than 16 KiB
+    /// are aligned to 4 KiB in endpoint memory, which may waste some memory.
+    ///
+    /// Returns [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if `ep_addr`
+    /// is a control endpoint, or if it's already allocated.
+    pub fn set_max_transfer_len(
+        &self,
+        ep_addr: EndpointAddress,
+        max_transfer_len: usize,
+    ) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.set_max_transfer_len(ep_addr, max_transfer_len))
+    }
+
+    /// Returns the maximum transfer length of an allocated endpoint
+    ///
+    /// Returns `None` if the endpoint isn't allocated. See
+    /// [`set_max_transfer_len`](BusAdapter::set_max_transfer_len) for more information.
+    pub fn max_transfer_len(&self, ep_addr: EndpointAddress) -> Option<usize> {
+        self.with_usb(|usb| usb.max_transfer_len(ep_addr))
+    }
+
+    /// Add endpoint memory for the endpoints selected by `placement`
+    ///
+    /// Returns the region's index, or `None` if the driver can't take another
+    /// region. The driver supports up to [`MAX_REGIONS`](crate::MAX_REGIONS) regions,
+    /// including the memory supplied to the constructor. See the [endpoint memory](BusAdapter#endpoint-memory)
+    /// documentation for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint memory has already been assigned to a USB driver.
+    pub fn add_endpoint_memory<const SIZE: usize>(
+        &self,
+        memory: &'static crate::EndpointMemory<SIZE>,
+        placement: Placement,
+    ) -> Option<usize> {
+        self.with_usb_mut(|usb| {
+            if !usb.has_endpoint_region_room() {
+                return None;
+            }
+            let allocator = memory
+                .allocator()
+                .expect("Endpoint memory already assigned");
+            usb.add_endpoint_region(allocator, placement)
+        })
+    }
+
+    /// Add a region of endpoint memory for the endpoints selected by `placement`
+    ///
+    /// Use this for memory that isn't an [`EndpointMemory`](crate::EndpointMemory),
+    /// like a linker-defined section. `policy` decides the D-cache maintenance for
+    /// the region's buffers. The region doesn't need to be aligned; the driver
+    /// aligns buffers within the region.
+    ///
+    /// Returns the region's index, or `None` if the driver can't take another
+    /// region. See [`add_endpoint_memory`](BusAdapter::add_endpoint_memory) for
+    /// more information.
+    pub fn add_endpoint_region(
+        &self,
+        region: &'static mut [u8],
+        policy: CachePolicy,
+        placement: Placement,
+    ) -> Option<usize> {
+        self.with_usb_mut(|usb| {
+            let allocator = crate::buffer::Allocator::new(region, policy);
+            usb.add_endpoint_region(allocator, placement)
+        })
+    }
+
+    /// Returns the endpoint memory and endpoint state usage
+    ///
+    /// Use this to size your [`EndpointMemory`](crate::EndpointMemory) and
+    /// [`EndpointState`](crate::EndpointState). Check the usage after your
+    /// classes allocate their endpoints.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.with_usb(|usb| usb.memory_usage())
+    }
+
+    /// Describe each allocated endpoint
+    ///
+    /// The table is indexed by the endpoint's index in the endpoint state.
+    /// Unallocated endpoints are `None`.
+    pub fn endpoint_usage(&self) -> [Option<EndpointUsage>; crate::MAX_ENDPOINTS] {
+        self.with_usb(|usb| usb.endpoint_usage())
+    }
+
+    /// Deallocate an endpoint, and reclaim its endpoint memory
+    ///
+    /// Cancels the endpoint's transfers, and disables the endpoint. The
+    /// endpoint's memory is available for [`reallocate_endpoint`](BusAdapter::reallocate_endpoint).
+    /// Your class must not use the endpoint until it's reallocated.
+    ///
+    /// Returns [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if
+    /// `ep_addr` is a control endpoint, or if it isn't allocated.
+    pub fn free_endpoint(&self, ep_addr: EndpointAddress) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.free_ep(ep_addr))
+    }
+
+    /// Reallocate an endpoint with a new type and max packet size
+    ///
+    /// Use this when the host selects an alternate setting that changes an
+    /// endpoint's max packet size. The driver frees the endpoint's memory, then
+    /// allocates new memory. If the endpoint was enabled, the reallocated
+    /// endpoint is enabled, too. Any transfers are cancelled.
+    ///
+    /// If the allocation fails, the endpoint is no longer allocated. Returns
+    /// [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if `ep_addr`
+    /// is a control endpoint, or [`EndpointMemoryOverflow`](usb_device::UsbError::EndpointMemoryOverflow)
+    /// if there isn't enough endpoint memory.
+    pub fn reallocate_endpoint(
+        &self,
+        ep_addr: EndpointAddress,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+    ) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.realloc_ep(ep_addr, ep_type, max_packet_size as usize))
+    }
+
+    /// Borrow the data received by an OUT endpoint, without copying it
+    ///
+    /// Returns a guard over the oldest completed transfer, holding the number of
+    /// bytes that the endpoint received. The endpoint doesn't receive into the
+    /// transfer's buffer until you drop the guard. Drop the guard to schedule the
+    /// next receive. Until then, the host sees the endpoint NAK once its other
+    /// transfers are full.
+    ///
+    /// Call this instead of [`read`](UsbBus::read) when `poll()` signals that the
+    /// endpoint received data. While the guard exists, `read` returns
+    /// [`WouldBlock`](usb_device::UsbError::WouldBlock) for this endpoint, and you
+    /// can't free or reallocate the endpoint.
+    ///
+    /// Returns [`WouldBlock`](usb_device::UsbError::WouldBlock) if there's no completed
+    /// transfer, or if the endpoint already lent a transfer. Returns
+    /// [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if `ep_addr` isn't a
+    /// non-zero, allocated OUT endpoint.
+    pub fn read_borrowed(&self, ep_addr: EndpointAddress) -> usb_device::Result<ReadGuard<'_>> {
+        if ep_addr.direction() != UsbDirection::Out {
+            return Err(usb_device::UsbError::InvalidEndpoint);
+        }
+        let buffer = self.with_usb_mut(|usb| usb.ep_lend(ep_addr))?;
+        // Safety: the endpoint doesn't receive into the lent buffer until
+        // the guard drops. The buffer is in static endpoint memory.
+        let data = unsafe { core::slice::from_raw_parts(buffer.as_ptr(), buffer.len()) };
+        Ok(ReadGuard {
+            bus: self,
+            ep_addr,
+            data,
+        })
+    }
+
+    /// Borrow the buffer of an IN endpoint's next transfer, and fill it in place
+    ///
+    /// Returns a guard over the idle transfer buffer, sized to the endpoint's
+    /// transfer length. Write your data into the guard, then
+    /// [`commit`](WriteGuard::commit) the number of bytes to send. Dropping the
+    /// guard without a commit sends nothing.
+    ///
+    /// While the guard exists, [`write`](UsbBus::write) returns
+    /// [`WouldBlock`](usb_device::UsbError::WouldBlock) for this endpoint, and you
+    /// can't free or reallocate the endpoint.
+    ///
+    /// Returns [`WouldBlock`](usb_device::UsbError::WouldBlock) while the endpoint
+    /// is busy with its scheduled transfers, or if the endpoint already lent a
+    /// buffer. Returns [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if
+    /// `ep_addr` isn't a non-zero, allocated IN endpoint.
+    pub fn write_borrowed(&self, ep_addr: EndpointAddress) -> usb_device::Result<WriteGuard<'_>> {
+        if ep_addr.direction() != UsbDirection::In {
+            return Err(usb_device::UsbError::InvalidEndpoint);
+        }
+        let mut buffer = self.with_usb_mut(|usb| usb.ep_lend(ep_addr))?;
+        // Safety: the endpoint doesn't send from the lent buffer until the
+        // guard commits. The buffer is in static endpoint memory.
+        let data = unsafe { core::slice::from_raw_parts_mut(buffer.as_ptr_mut(), buffer.len()) };
+        Ok(WriteGuard {
+            bus: self,
+            ep_addr,
+            data,
+        })
+    }
+
     /// Immutable access to the USB peripheral
     fn with_usb<R>(&self, func: impl FnOnce(&Driver) -> R) -> R {
         let with_cs = |cs: &'_ _| {
@@ -265,6 +526,284 @@ impl BusAdapter {
         });
     }
 
+    /// Returns the status of an endpoint, including the errors of its transfers
+    ///
+    /// When [`read`](UsbBus::read) or [`write`](UsbBus::write) return
+    /// [`InvalidState`](usb_device::UsbError::InvalidState), use this to learn which
+    /// TD status bits fired. The status also counts the errors of all failed
+    /// transfers. Returns `None` if the endpoint isn't allocated.
+    pub fn endpoint_status(&self, ep_addr: EndpointAddress) -> Option<EndpointStatus> {
+        self.with_usb_mut(|usb| usb.ep_status(ep_addr))
+    }
+
+    /// Recover an endpoint after a failed transfer, without a bus reset
+    ///
+    /// After a transfer fails, [`read`](UsbBus::read) and [`write`](UsbBus::write)
+    /// return [`InvalidState`](usb_device::UsbError::InvalidState) until the endpoint
+    /// recovers. Recovery flushes the endpoint, drops its scheduled transfers, and
+    /// clears the transfer status. If `reset_data_toggle` is set, the endpoint's
+    /// next transaction uses DATA0. An OUT endpoint is then ready to receive. An IN
+    /// endpoint doesn't send its dropped transfers; write them again.
+    ///
+    /// Returns [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if `ep_addr`
+    /// is a control endpoint, or if it isn't allocated.
+    pub fn recover_endpoint(
+        &self,
+        ep_addr: EndpointAddress,
+        reset_data_toggle: bool,
+    ) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.recover_ep(ep_addr, reset_data_toggle))
+    }
+
+    /// Reset an endpoint's data toggle, so that its next transaction uses DATA0
+    ///
+    /// Unstalling an endpoint already resets its data toggle. Use this when a class
+    /// protocol resynchronizes the toggle without a halt.
+    ///
+    /// Returns [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if `ep_addr`
+    /// is a control endpoint, or if it isn't allocated.
+    pub fn reset_data_toggle(&self, ep_addr: EndpointAddress) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.reset_ep_data_toggle(ep_addr))
+    }
+
+    /// Cancel the transfers scheduled on an endpoint
+    ///
+    /// Use this to drop IN data that went stale before the host read it. This flushes
+    /// only `ep_addr`, waits for the controller to stop processing the endpoint, and
+    /// drops its scheduled transfers. Returns the number of bytes that the dropped
+    /// transfers already moved, including part of a transfer that was in progress.
+    /// Afterwards, an IN endpoint is ready to [`write`](UsbBus::write) again. An OUT
+    /// endpoint discards its unread data, and is ready to receive.
+    ///
+    /// A buffer lent by [`read_borrowed`](BusAdapter::read_borrowed) or
+    /// [`write_borrowed`](BusAdapter::write_borrowed) stays lent.
+    ///
+    /// Returns [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if `ep_addr`
+    /// is a control endpoint, or if it isn't allocated.
+    pub fn cancel(&self, ep_addr: EndpointAddress) -> usb_device::Result<usize> {
+        self.with_usb_mut(|usb| usb.cancel_ep(ep_addr))
+    }
+
+    /// Recover endpoints automatically after a failed transfer
+    ///
+    /// When enabled, a [`read`](UsbBus::read) or [`write`](UsbBus::write) that finds
+    /// a failed transfer recovers the endpoint, keeping the data toggle. The call
+    /// still returns [`InvalidState`](usb_device::UsbError::InvalidState), and the
+    /// next call uses the recovered endpoint. By default, automatic recovery is off.
+    /// See [`recover_endpoint`](BusAdapter::recover_endpoint) for more information.
+    pub fn set_automatic_recovery(&self, enable: bool) {
+        self.with_usb_mut(|usb| usb.set_auto_recovery(enable));
+    }
+
+    /// Take the most recent isochronous transfer error for an endpoint
+    ///
+    /// Isochronous endpoints don't halt when a transfer fails. Instead, the
+    /// driver drops the failed transfer, and records the error. Returns `None`
+    /// if the endpoint isn't allocated, or if there was no error since the last
+    /// call.
+    pub fn take_isochronous_error(&self, ep_addr: EndpointAddress) -> Option<IsochronousError> {
+        self.with_usb_mut(|usb| usb.take_isochronous_error(ep_addr))
+    }
+
+    /// Returns the (micro)frame of an isochronous endpoint's first transfer
+    ///
+    /// `poll()` primes an idle isochronous endpoint just after a (micro)frame
+    /// boundary, so the first transfer moves in the next (micro)frame. Returns
+    /// `None` if the endpoint isn't allocated, if it isn't isochronous, or if
+    /// its prime still waits for the boundary.
+    pub fn isochronous_start_frame(&self, ep_addr: EndpointAddress) -> Option<Frame> {
+        self.with_usb(|usb| usb.isochronous_start_frame(ep_addr))
+    }
+
+    /// Enable (`true`) or disable (`false`) start-of-frame (SOF) interrupts
+    ///
+    /// usb-device has no SOF event. Instead, `poll()` records each SOF, and
+    /// you can learn about it with [`take_sof`](BusAdapter::take_sof). When a SOF
+    /// is the only event, `poll()` reports data without any endpoints, so
+    /// `UsbDevice::poll` returns `true` and polls your classes. Expect a SOF
+    /// every millisecond at full speed, and every microframe (125us) at high
+    /// speed. SOF interrupts are off by default.
+    pub fn set_sof_interrupts(&self, interrupts: bool) {
+        self.with_usb_mut(|usb| usb.set_sof_interrupts(interrupts));
+    }
+
+    /// Indicates if `poll()` saw a start-of-frame (SOF) since the last call
+    ///
+    /// Use [`frame_number`](BusAdapter::frame_number) and [`microframe`](BusAdapter::microframe)
+    /// to learn which frame started. This only reports SOFs if you enable
+    /// SOF interrupts, or if you frequently poll.
+    pub fn take_sof(&self) -> bool {
+        self.with_usb_mut(|usb| usb.take_sof())
+    }
+
+    /// Enable (`true`) or disable (`false`) NAK interrupts for an endpoint
+    ///
+    /// usb-device has no NAK event. Instead, `poll()` records each endpoint that
+    /// NAKed the host, and you can learn about them with [`take_nak_events`](BusAdapter::take_nak_events).
+    /// When a NAK is the only event, `poll()` reports data without any endpoints,
+    /// so `UsbDevice::poll` returns `true` and polls your classes.
+    /// An IN endpoint NAKs when the host polls it while nothing is primed, so you
+    /// can write data only when the host asks for it. An OUT endpoint NAKs when the
+    /// host sends data while the endpoint has no free TD. NAK interrupts are off
+    /// by default. A bus reset, or a detach, disables NAK interrupts on every
+    /// endpoint, so enable them again once the host configures the device.
+    ///
+    /// Returns [`InvalidEndpoint`](usb_device::UsbError::InvalidEndpoint) if `ep_addr`
+    /// isn't allocated. Freeing the endpoint disables its NAK interrupts.
+    pub fn set_nak_interrupts(
+        &self,
+        ep_addr: EndpointAddress,
+        interrupts: bool,
+    ) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.set_nak_interrupts(ep_addr, interrupts))
+    }
+
+    /// Returns the endpoints that `poll()` saw NAK the host since the last call
+    ///
+    /// Only reports endpoints with NAK interrupts.
+    pub fn take_nak_events(&self) -> NakEvents {
+        self.with_usb_mut(|usb| usb.take_nak_events())
+    }
+
+    /// Returns the current 11-bit USB frame number
+    pub fn frame_number(&self) -> u16 {
+        self.with_usb(|usb| usb.frame_number())
+    }
+
+    /// Returns the current microframe, 0 through 7
+    ///
+    /// This is always zero at low and full speed.
+    pub fn microframe(&self) -> u8 {
+        self.with_usb(|usb| usb.microframe())
+    }
+
+    /// Returns the bus speed negotiated with the host
+    ///
+    /// The driver learns the speed at the end of each bus reset. Returns `None`
+    /// before the first reset finishes, while a reset is in progress, or while the
+    /// device is detached. The speed can differ from the [`Speed`] that you
+    /// request when you create the bus adapter; a high-speed device attached to a
+    /// full-speed hub runs at full speed.
+    pub fn negotiated_speed(&self) -> Option<BusSpeed> {
+        self.with_usb(|usb| usb.negotiated_speed())
+    }
+
+    /// Remove the device from the bus
+    ///
+    /// Turns off the D+ pull-up, cancels all primed transfers, and
+    /// reinitializes all endpoints. The host sees a disconnect. Use
+    /// [`attach`](BusAdapter::attach) to reconnect; the host then resets
+    /// and enumerates the device, and you must [`configure`](BusAdapter::configure)
+    /// the device again.
+    pub fn detach(&self) {
+        self.with_usb_mut(|usb| usb.detach());
+    }
+
+    /// Connect the device to the bus
+    ///
+    /// Turns on the D+ pull-up. If VBUS detection is enabled, the driver
+    /// waits for VBUS before it connects.
+    pub fn attach(&self) {
+        self.with_usb_mut(|usb| usb.attach());
+    }
+
+    /// Let `UsbBus::force_reset` detach, then re-attach after `detach_us`
+    ///
+    /// `timer` times the detach, so you must not use that GPT for anything
+    /// else. The timer's interrupt causes a USB interrupt, and `poll()`
+    /// re-attaches the device once the timer elapses. The USB specification
+    /// does not specify a minimum time, but hosts commonly require a
+    /// disconnect of at least a few milliseconds.
+    ///
+    /// Without a force reset timer, `force_reset` returns `Unsupported`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` debounces VBUS, or if it times remote wakeup signaling.
+    pub fn set_force_reset_timer(&self, timer: gpt::Instance, detach_us: u32) {
+        self.with_usb_mut(|usb| usb.set_force_reset_timer(timer, detach_us));
+    }
+
+    /// Monitor VBUS, and automatically attach and detach from the bus
+    ///
+    /// Use this on self-powered devices, so that the device only pulls up D+
+    /// when a host supplies VBUS. The driver monitors the OTG B session valid
+    /// state, `OTGSC[BSV]`, and, if you supply it, the USB analog VBUS detect
+    /// status; see [`set_vbus_detect_status`](BusAdapter::set_vbus_detect_status).
+    /// A B session valid change starts a debounce. `timer` debounces VBUS for 10ms,
+    /// so you must not use that GPT for anything else. The timer's interrupt
+    /// causes a USB interrupt. When VBUS goes away, the driver detaches like
+    /// [`detach`](BusAdapter::detach), but it attaches again once VBUS returns.
+    ///
+    /// Call this before you build your USB device. If VBUS is absent, the
+    /// device doesn't attach until VBUS is present.
+    ///
+    /// usb-device's `PollResult` has no attach or detach events. So `poll()`
+    /// reports a detach as a suspend, and reports nothing for an attach; the
+    /// host resets the device once it's attached. Use [`take_vbus_event`](BusAdapter::take_vbus_event)
+    /// to learn about attach and detach events.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` is the force reset timer, or if it times remote wakeup
+    /// signaling.
+    pub fn enable_vbus_detection(&self, timer: gpt::Instance) {
+        self.with_usb_mut(|usb| usb.enable_vbus_detection(timer));
+    }
+
+    /// Also require the USB analog VBUS detector's `VBUS_VALID` status
+    ///
+    /// With VBUS detection, the driver only considers VBUS present when both the
+    /// B session is valid, and `status` reports `VBUS_VALID`. `status` is the VBUS
+    /// detect status register for this USB instance. On the i.MX RT 10xx, it's in
+    /// the USB_ANALOG block; on the 11xx, it's in the USBPHY block. Call this before
+    /// [`enable_vbus_detection`](BusAdapter::enable_vbus_detection).
+    ///
+    /// ```no_run
+    /// use imxrt_ral as ral;
+    /// # fn vbus(bus_adapter: &imxrt_usbd::BusAdapter) {
+    ///
+    /// // Safety: the driver only reads the register.
+    /// let status = unsafe { &(*ral::usb_analog::USB_ANALOG).USB1_VBUS_DETECT_STAT };
+    /// bus_adapter.set_vbus_detect_status(status);
+    /// # }
+    /// ```
+    pub fn set_vbus_detect_status(&self, status: &'static imxrt_ral::RORegister<u32>) {
+        self.with_usb_mut(|usb| usb.set_vbus_detect_status(status));
+    }
+
+    /// Indicates if VBUS is present
+    ///
+    /// Returns `None` if VBUS detection isn't enabled.
+    pub fn is_vbus_present(&self) -> Option<bool> {
+        self.with_usb(|usb| usb.is_vbus_present())
+    }
+
+    /// Take the most recent VBUS event
+    ///
+    /// Returns `None` if VBUS detection isn't enabled, or if there was no
+    /// attach or detach since the last call.
+    pub fn take_vbus_event(&self) -> Option<VbusEvent> {
+        self.with_usb_mut(|usb| usb.take_vbus_event())
+    }
+
+    /// Wake the host from suspend
+    ///
+    /// Drives resume signaling on the bus for 10ms. `timer` times the signaling,
+    /// so you must not use that GPT until `poll()` reports a resume. The timer's
+    /// interrupt causes a USB interrupt, which ends the signaling.
+    ///
+    /// Only signal remote wakeup if the host enabled the feature; see
+    /// `UsbDevice::remote_wakeup_enabled()`. The USB specification also requires
+    /// that the bus is idle for at least 5ms before signaling. The driver reports
+    /// a suspend after 3ms of idle.
+    ///
+    /// Returns `InvalidState` if the bus isn't suspended, or if the driver is
+    /// already signaling remote wakeup.
+    pub fn remote_wakeup(&self, timer: gpt::Instance) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.remote_wakeup(timer))
+    }
+
     /// Acquire one of the GPT timer instances.
     ///
     /// `instance` identifies which GPT instance you're accessing.
@@ -279,6 +818,82 @@ impl BusAdapter {
     }
 }
 
+/// Data received by an OUT endpoint
+///
+/// Dereferences to the received bytes. Dropping the guard lets the endpoint
+/// receive into the memory again. See [`BusAdapter::read_borrowed`].
+pub struct ReadGuard<'a> {
+    bus: &'a BusAdapter,
+    ep_addr: EndpointAddress,
+    data: &'a [u8],
+}
+
+impl ReadGuard<'_> {
+    /// Returns the endpoint that received the data
+    pub fn endpoint(&self) -> EndpointAddress {
+        self.ep_addr
+    }
+}
+
+impl core::ops::Deref for ReadGuard<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        self.bus.with_usb_mut(|usb| usb.ep_release(self.ep_addr));
+    }
+}
+
+/// The buffer of an IN endpoint's next transfer
+///
+/// Dereferences to the transfer buffer. See [`BusAdapter::write_borrowed`].
+pub struct WriteGuard<'a> {
+    bus: &'a BusAdapter,
+    ep_addr: EndpointAddress,
+    data: &'a mut [u8],
+}
+
+impl WriteGuard<'_> {
+    /// Returns the endpoint that sends the data
+    pub fn endpoint(&self) -> EndpointAddress {
+        self.ep_addr
+    }
+
+    /// Send the first `len` bytes of the buffer
+    ///
+    /// Returns the number of bytes scheduled, which is constrained by the
+    /// buffer length. Returns [`InvalidState`](usb_device::UsbError::InvalidState)
+    /// if the endpoint is disabled or stalled, or if its oldest transfer failed.
+    /// After an error, the endpoint doesn't send the buffer.
+    pub fn commit(self, len: usize) -> usb_device::Result<usize> {
+        self.bus
+            .with_usb_mut(|usb| usb.ep_commit(self.ep_addr, len))
+    }
+}
+
+impl core::ops::Deref for WriteGuard<'_> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.data
+    }
+}
+
+impl core::ops::DerefMut for WriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.data
+    }
+}
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        self.bus.with_usb_mut(|usb| usb.ep_release(self.ep_addr));
+    }
+}
+
 impl UsbBus for BusAdapter {
     /// The USB hardware can guarantee that we set the status before we receive
     /// the status, and we're taking advantage of that. We expect this flag to
@@ -296,31 +911,7 @@ impl UsbBus for BusAdapter {
         max_packet_size: u16,
         _interval: u8,
     ) -> usb_device::Result<EndpointAddress> {
-        self.with_usb_mut(|usb| {
-            if let Some(addr) = ep_addr {
-                if usb.is_allocated(addr) {
-                    return Err(usb_device::UsbError::InvalidEndpoint);
-                }
-                let buffer = usb
-                    .allocate_buffer(max_packet_size as usize)
-                    .ok_or(usb_device::UsbError::EndpointMemoryOverflow)?;
-                usb.allocate_ep(addr, buffer, ep_type);
-                Ok(addr)
-            } else {
-                for idx in 1..8 {
-                    let addr = EndpointAddress::from_parts(idx, ep_dir);
-                    if usb.is_allocated(addr) {
-                        continue;
-                    }
-                    let buffer = usb
-                        .allocate_buffer(max_packet_size as usize)
-                        .ok_or(usb_device::UsbError::EndpointMemoryOverflow)?;
-                    usb.allocate_ep(addr, buffer, ep_type);
-                    return Ok(addr);
-                }
-                Err(usb_device::UsbError::EndpointOverflow)
-            }
-        })
+        self.with_usb_mut(|usb| usb.alloc_ep(ep_dir, ep_addr, ep_type, max_packet_size as usize))
     }
 
     fn set_device_address(&self, addr: u8) {
@@ -339,6 +930,11 @@ impl UsbBus for BusAdapter {
         });
     }
 
+    /// See [`set_force_reset_timer`](BusAdapter::set_force_reset_timer)
+    fn force_reset(&self) -> usb_device::Result<()> {
+        self.with_usb_mut(|usb| usb.force_reset())
+    }
+
     fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
         self.with_usb_mut(|usb| {
             if !usb.is_allocated(ep_addr) {
@@ -400,11 +996,11 @@ impl UsbBus for BusAdapter {
     }
 
     fn suspend(&self) {
-        // TODO
+        self.with_usb_mut(|usb| usb.suspend());
     }
 
     fn resume(&self) {
-        // TODO
+        self.with_usb_mut(|usb| usb.resume());
     }
 
     fn poll(&self) -> PollResult {
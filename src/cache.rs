@@ -13,44 +13,283 @@
 //!
 //! <https://github.com/rust-embedded/cortex-m/pull/320> indicates that this might
 //! be available in a near-future cortex-m crate.
+//!
+//! # Protocol
+//!
+//! The CPU and the USB controller share endpoint buffers, QHs, and TDs.
+//!
+//! - Before the controller reads memory, *clean* it, so that the controller
+//!   sees the CPU's writes. IN buffers are cleaned before they're primed.
+//! - Before the CPU reads memory written by the controller, *invalidate* it,
+//!   so that the CPU doesn't read stale lines. OUT buffers are invalidated
+//!   after a transfer completes.
+//! - QHs and TDs are written by both the CPU and the controller, so they're
+//!   cleaned and invalidated.
+//!
+//! A [`CachePolicy`] decides if an operation touches the D-cache. All operations
+//! go through a [`Backend`]. Host tests replace the Cortex-M7 backend with a
+//! backend that records each operation.
 
-/// Cleans and invalidates D-cache by address.
-///
-/// * `addr`: The address to clean and invalidate.
-/// * `size`: The number of bytes to clean and invalidate.
-///
-/// Cleans and invalidates D-cache starting from the first cache line containing `addr`,
-/// finishing once at least `size` bytes have been cleaned and invalidated.
+/// A D-cache maintenance operation
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Operation {
+    /// Write dirty lines back to main memory.
+    Clean,
+    /// Discard lines, without writing them back to main memory.
+    Invalidate,
+    /// Write dirty lines back to main memory, then discard them.
+    CleanInvalidate,
+}
+
+/// Performs D-cache maintenance by address
+pub trait Backend {
+    /// Perform `operation` on every cache line that holds any of the `size`
+    /// bytes starting at `addr`.
+    ///
+    /// `size` is never zero.
+    fn maintain(operation: Operation, addr: usize, size: usize);
+
+    /// Indicates if the D-cache is enabled
+    fn is_enabled() -> bool;
+}
+
+/// Maintains the Cortex-M7 D-cache
+#[cfg_attr(test, allow(dead_code))]
+pub struct CortexM7;
+
+impl Backend for CortexM7 {
+    fn maintain(operation: Operation, addr: usize, size: usize) {
+        // Safety: write-only registers, pointer to static memory
+        let cbp = unsafe { &*cortex_m::peripheral::CBP::PTR };
+
+        cortex_m::asm::dsb();
+
+        // Cache lines are fixed to 32 bytes on Cortex-M7, and not present in earlier Cortex-M.
+        const LINESIZE: usize = crate::buffer::CACHE_LINE_SIZE;
+        let mut line = addr & !(LINESIZE - 1);
+        let end = addr + size;
+        while line < end {
+            // Safety: write to Cortex-M write-only register
+            unsafe {
+                match operation {
+                    Operation::Clean => cbp.dccmvac.write(line as u32),
+                    Operation::Invalidate => cbp.dcimvac.write(line as u32),
+                    Operation::CleanInvalidate => cbp.dccimvac.write(line as u32),
+                }
+            };
+            line += LINESIZE;
+        }
+
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+
+    fn is_enabled() -> bool {
+        cortex_m::peripheral::SCB::dcache_enabled()
+    }
+}
+
+#[cfg(not(test))]
+type Active = CortexM7;
+#[cfg(test)]
+type Active = recorder::Recorder;
+
+/// D-cache maintenance policy for endpoint memory and endpoint state
 ///
-/// It is recommended that `addr` is aligned to the cache line size and `size` is a multiple of
-/// the cache line size, otherwise surrounding data will also be cleaned.
+/// By default, the driver always maintains the D-cache. If you place
+/// [`EndpointMemory`](crate::EndpointMemory) or [`EndpointState`](crate::EndpointState)
+/// in non-cacheable memory, like DTCM or an MPU region that's non-cacheable, you can
+/// skip the maintenance. If the driver runs on a core without a D-cache, like the
+/// i.MX RT 1170's Cortex-M4, you must skip or detect.
 ///
-/// Cleaning and invalidating causes data in the D-cache to be written back to main memory,
-/// and then marks that data in the D-cache as invalid, causing future reads to first fetch
-/// from main memory.
-pub fn clean_invalidate_dcache_by_address(addr: usize, size: usize) {
-    // No-op zero sized operations
-    if size == 0 {
-        return;
+/// Enable this package's `skip-dcache-maintenance` feature to make [`Skip`](CachePolicy::Skip)
+/// the default policy.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CachePolicy {
+    /// Always maintain the D-cache.
+    ///
+    /// This requires a Cortex-M7.
+    Maintain,
+    /// Never maintain the D-cache.
+    ///
+    /// Use this if the memory is non-cacheable, or if the core has no D-cache.
+    Skip,
+    /// Maintain the D-cache only if it's enabled.
+    ///
+    /// Before each operation, the driver checks the D-cache enable bit,
+    /// `SCB.CCR.DC`. This is always correct, but it costs a register read
+    /// per operation.
+    Detect,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl CachePolicy {
+    /// The default policy
+    ///
+    /// This is [`Maintain`](CachePolicy::Maintain), or [`Skip`](CachePolicy::Skip)
+    /// if the `skip-dcache-maintenance` feature is enabled.
+    pub const DEFAULT: Self = if cfg!(feature = "skip-dcache-maintenance") {
+        Self::Skip
+    } else {
+        Self::Maintain
+    };
+
+    /// Perform `operation` with the active backend, if this policy allows it
+    fn maintain(self, operation: Operation, addr: usize, size: usize) {
+        // No-op zero sized operations
+        if size == 0 {
+            return;
+        }
+        let maintain = match self {
+            Self::Maintain => true,
+            Self::Skip => false,
+            Self::Detect => Active::is_enabled(),
+        };
+        if maintain {
+            Active::maintain(operation, addr, size);
+        }
     }
 
-    // Safety: write-only registers, pointer to static memory
-    let cbp = unsafe { &*cortex_m::peripheral::CBP::PTR };
+    /// Cleans D-cache by address.
+    ///
+    /// * `addr`: The address to clean.
+    /// * `size`: The number of bytes to clean.
+    ///
+    /// Cleans D-cache starting from the first cache line containing `addr`,
+    /// finishing once at least `size` bytes have been cleaned.
+    ///
+    /// Cleaning causes dirty data in the D-cache to be written back to main memory.
+    /// Use this before the controller reads memory that the CPU wrote.
+    pub(crate) fn clean_dcache_by_address(self, addr: usize, size: usize) {
+        self.maintain(Operation::Clean, addr, size);
+    }
 
-    cortex_m::asm::dsb();
+    /// Invalidates D-cache by address.
+    ///
+    /// * `addr`: The address to invalidate.
+    /// * `size`: The number of bytes to invalidate.
+    ///
+    /// Invalidates D-cache starting from the first cache line containing `addr`,
+    /// finishing once at least `size` bytes have been invalidated.
+    ///
+    /// Invalidating marks data in the D-cache as invalid, causing future reads to
+    /// fetch from main memory. Dirty data is discarded, so only invalidate lines that
+    /// the CPU doesn't write. Use this before the CPU reads memory that the controller
+    /// wrote.
+    pub(crate) fn invalidate_dcache_by_address(self, addr: usize, size: usize) {
+        self.maintain(Operation::Invalidate, addr, size);
+    }
+
+    /// Cleans and invalidates D-cache by address.
+    ///
+    /// * `addr`: The address to clean and invalidate.
+    /// * `size`: The number of bytes to clean and invalidate.
+    ///
+    /// Cleans and invalidates D-cache starting from the first cache line containing `addr`,
+    /// finishing once at least `size` bytes have been cleaned and invalidated.
+    ///
+    /// It is recommended that `addr` is aligned to the cache line size and `size` is a multiple of
+    /// the cache line size, otherwise surrounding data will also be cleaned.
+    ///
+    /// Cleaning and invalidating causes data in the D-cache to be written back to main memory,
+    /// and then marks that data in the D-cache as invalid, causing future reads to first fetch
+    /// from main memory.
+    pub(crate) fn clean_invalidate_dcache_by_address(self, addr: usize, size: usize) {
+        self.maintain(Operation::CleanInvalidate, addr, size);
+    }
+}
 
-    // Cache lines are fixed to 32 bit on Cortex-M7 and not present in earlier Cortex-M
-    const LINESIZE: usize = 32;
-    let num_lines = ((size - 1) / LINESIZE) + 1;
+/// A backend that records operations, for host tests.
+#[cfg(test)]
+pub(crate) mod recorder {
+    extern crate std;
 
-    let mut addr = addr & 0xFFFF_FFE0;
+    use super::{Backend, Operation};
+    use core::cell::{Cell, RefCell};
+    use std::vec::Vec;
+
+    std::thread_local! {
+        static OPERATIONS: RefCell<Vec<(Operation, usize, usize)>> = const { RefCell::new(Vec::new()) };
+        static ENABLED: Cell<bool> = const { Cell::new(true) };
+    }
+
+    /// Records each operation for the calling thread.
+    pub struct Recorder;
+
+    impl Backend for Recorder {
+        fn maintain(operation: Operation, addr: usize, size: usize) {
+            OPERATIONS.with_borrow_mut(|ops| ops.push((operation, addr, size)));
+        }
+
+        fn is_enabled() -> bool {
+            ENABLED.get()
+        }
+    }
 
-    for _ in 0..num_lines {
-        // Safety: write to Cortex-M write-only register
-        unsafe { cbp.dccimvac.write(addr as u32) };
-        addr += LINESIZE;
+    /// Simulate an enabled, or disabled, D-cache for this thread.
+    pub fn set_enabled(enabled: bool) {
+        ENABLED.set(enabled);
     }
 
-    cortex_m::asm::dsb();
-    cortex_m::asm::isb();
+    /// Take the operation, address, and size of each operation recorded on
+    /// this thread.
+    pub fn take() -> Vec<(Operation, usize, usize)> {
+        OPERATIONS.take()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CachePolicy, Operation, recorder};
+
+    #[test]
+    fn record_operations() {
+        recorder::take();
+        let policy = CachePolicy::Maintain;
+        policy.clean_dcache_by_address(0x2000_0000, 64);
+        policy.invalidate_dcache_by_address(0x2000_0040, 7);
+        policy.clean_invalidate_dcache_by_address(0x2000_0080, 32);
+        assert_eq!(
+            recorder::take(),
+            [
+                (Operation::Clean, 0x2000_0000, 64),
+                (Operation::Invalidate, 0x2000_0040, 7),
+                (Operation::CleanInvalidate, 0x2000_0080, 32),
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_zero_sized() {
+        recorder::take();
+        CachePolicy::Maintain.clean_dcache_by_address(0x2000_0000, 0);
+        CachePolicy::Maintain.invalidate_dcache_by_address(0x2000_0000, 0);
+        assert!(recorder::take().is_empty());
+    }
+
+    #[test]
+    fn skip_policy() {
+        recorder::take();
+        CachePolicy::Skip.clean_dcache_by_address(0x2000_0000, 32);
+        CachePolicy::Skip.invalidate_dcache_by_address(0x2000_0000, 32);
+        CachePolicy::Skip.clean_invalidate_dcache_by_address(0x2000_0000, 32);
+        assert!(recorder::take().is_empty());
+    }
+
+    #[test]
+    fn detect_policy() {
+        recorder::take();
+        recorder::set_enabled(false);
+        CachePolicy::Detect.clean_dcache_by_address(0x2000_0000, 32);
+        assert!(recorder::take().is_empty());
+
+        recorder::set_enabled(true);
+        CachePolicy::Detect.clean_dcache_by_address(0x2000_0000, 32);
+        assert_eq!(recorder::take(), [(Operation::Clean, 0x2000_0000, 32)]);
+    }
 }
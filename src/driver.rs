@@ -4,7 +4,8 @@
 //! bus behaviors, so that it could be used separately. However, it's
 //! not yet exposed in the package's API.
 
-use crate::{buffer, gpt, ral};
+pub use crate::endpoint::{ErrorCounts, IsochronousError, TransferError};
+use crate::{buffer, gpt, ral, td};
 use usb_device::{
     UsbDirection, UsbError,
     bus::PollResult,
@@ -39,6 +40,192 @@ pub enum Speed {
     High,
 }
 
+/// The bus speed negotiated with the host
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BusSpeed {
+    /// Low speed (1.5 Mbit/s).
+    Low,
+    /// Full speed (12 Mbit/s).
+    Full,
+    /// High speed (480 Mbit/s).
+    High,
+}
+
+/// A change in the VBUS session
+///
+/// See [`BusAdapter::enable_vbus_detection`](crate::BusAdapter::enable_vbus_detection).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum VbusEvent {
+    /// VBUS is present. The driver attached to the bus.
+    Attached,
+    /// VBUS is absent. The driver detached from the bus.
+    Detached,
+}
+
+/// Endpoint memory and endpoint state usage
+///
+/// See [`BusAdapter::memory_usage`](crate::BusAdapter::memory_usage).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryUsage {
+    /// The size of the endpoint memory, in bytes.
+    ///
+    /// This, and the other memory sizes, sum all endpoint memory regions.
+    pub total: usize,
+    /// Bytes reserved by endpoint buffers, including padding.
+    pub used: usize,
+    /// Bytes available for endpoint buffers.
+    ///
+    /// The free memory may be fragmented, so an allocation of this size
+    /// may still fail.
+    pub free: usize,
+    /// The most bytes that were ever used.
+    ///
+    /// This sums the high water mark of each region.
+    pub high_water_mark: usize,
+    /// The number of endpoints supported by the endpoint state.
+    pub endpoint_capacity: usize,
+    /// The number of allocated endpoints.
+    pub endpoints_allocated: usize,
+    /// The smallest endpoint state `COUNT` that holds every endpoint that was
+    /// ever allocated.
+    pub endpoints_required: usize,
+}
+
+/// An allocated endpoint, and its buffer
+///
+/// See [`BusAdapter::endpoint_usage`](crate::BusAdapter::endpoint_usage).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EndpointUsage {
+    /// The endpoint address.
+    pub address: EndpointAddress,
+    /// The endpoint type.
+    pub kind: EndpointType,
+    /// The address of the endpoint buffer.
+    pub buffer_address: usize,
+    /// The length of the endpoint buffer, in bytes.
+    ///
+    /// This holds one transfer for each of the endpoint's transfer
+    /// descriptors.
+    pub buffer_len: usize,
+    /// Bytes reserved for the endpoint buffer, including padding.
+    pub reserved: usize,
+    /// The endpoint memory region that holds the buffer.
+    ///
+    /// Region zero is the endpoint memory supplied to the driver's constructor.
+    pub region: usize,
+    /// The endpoint's index in the endpoint state.
+    pub state_index: usize,
+}
+
+/// The status of an allocated endpoint
+///
+/// See [`BusAdapter::endpoint_status`](crate::BusAdapter::endpoint_status).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct EndpointStatus {
+    /// The errors of the oldest scheduled transfer, if it failed.
+    ///
+    /// While a non-isochronous transfer has an error, reads and writes on
+    /// the endpoint return `InvalidState`.
+    pub error: Option<TransferError>,
+    /// The errors of all failed transfers since the endpoint was allocated.
+    pub error_counts: ErrorCounts,
+    /// The endpoint is stalled.
+    pub stalled: bool,
+    /// The endpoint is primed, or it's being primed.
+    pub primed: bool,
+}
+
+/// Endpoints that NAKed the host
+///
+/// Bit N represents endpoint N. See
+/// [`BusAdapter::set_nak_interrupts`](crate::BusAdapter::set_nak_interrupts).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct NakEvents {
+    /// IN endpoints that the host polled while they had nothing primed.
+    pub ep_in: u16,
+    /// OUT endpoints that the host sent data to while they had no free TD.
+    pub ep_out: u16,
+}
+
+impl NakEvents {
+    /// Indicates if no endpoint NAKed the host
+    pub fn is_empty(&self) -> bool {
+        self.ep_in == 0 && self.ep_out == 0
+    }
+
+    /// Indicates if the endpoint NAKed the host
+    pub fn contains(&self, addr: EndpointAddress) -> bool {
+        let mask = match addr.direction() {
+            UsbDirection::In => self.ep_in,
+            UsbDirection::Out => self.ep_out,
+        };
+        mask & (1 << addr.index()) != 0
+    }
+}
+
+/// A USB (micro)frame
+///
+/// See [`BusAdapter::isochronous_start_frame`](crate::BusAdapter::isochronous_start_frame).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Frame {
+    /// The 11-bit frame number.
+    pub number: u16,
+    /// The microframe, 0 through 7. Always zero at low and full speed.
+    pub microframe: u8,
+}
+
+/// VBUS session monitoring state
+struct VbusDetection {
+    /// Debounces VBUS changes.
+    timer: gpt::Instance,
+    /// The debounced VBUS session state.
+    attached: bool,
+    /// The most recent session change, not yet taken by the user.
+    event: Option<VbusEvent>,
+}
+
+/// How long VBUS must be stable before the driver attaches or detaches
+const VBUS_DEBOUNCE_US: u32 = 10_000;
+
+/// OTGSC interrupt status bits, which clear when written with 1
+const OTGSC_STATUS: u32 = {
+    use ral::usb::OTGSC;
+    OTGSC::IDIS::mask
+        | OTGSC::AVVIS::mask
+        | OTGSC::ASVIS::mask
+        | OTGSC::BSVIS::mask
+        | OTGSC::BSEIS::mask
+        | OTGSC::STATUS_1MS::mask
+        | OTGSC::DPIS::mask
+};
+
+/// A USB analog VBUS detect status register
+struct VbusDetectStat(&'static ral::RORegister<u32>);
+
+// Safety: the register is MMIO, and the driver only reads it.
+unsafe impl Send for VbusDetectStat {}
+
+impl VbusDetectStat {
+    /// The `VBUS_VALID` bit, which is in the same place for every USB
+    /// instance, and on every chip.
+    const VBUS_VALID: u32 = 1 << 3;
+
+    /// Indicates if the analog VBUS detector reports valid VBUS
+    fn is_vbus_valid(&self) -> bool {
+        self.0.read() & Self::VBUS_VALID != 0
+    }
+}
+
+/// Force reset configuration
+struct ForceReset {
+    /// Times the detach.
+    timer: gpt::Instance,
+    /// How long the pull-up is off.
+    detach_us: u32,
+    /// Set while the driver is detached, waiting to re-attach.
+    pending: bool,
+}
+
 /// A USB driver
 ///
 /// After you allocate a `Driver` with [`new()`](Driver::new), you must
@@ -48,7 +235,7 @@ pub enum Speed {
 pub struct Driver {
     usb: ral::AnyUsbInstance,
     phy: ral::AnyUsbphyInstance,
-    buffer_allocator: buffer::Allocator,
+    buffers: buffer::Regions,
     ep_allocator: crate::state::EndpointAllocator<'static>,
     /// Track which read endpoints have completed, so as to not
     /// confuse the device and appear out of sync with poll() calls.
@@ -60,8 +247,53 @@ pub struct Driver {
     /// it would return data. The usb-device test_class treats that as
     /// a failure, so we should keep behaviors consistent.
     ep_out: u16,
+    /// OUT endpoints that still hold completed, unread transfers after
+    /// an ep_read() call.
+    ///
+    /// An endpoint with more than one TD can complete several transfers
+    /// before the class reads the first one. poll() keeps signaling these
+    /// endpoints until the class has read every completed transfer.
+    ep_out_pending: u16,
+    /// The user's requested transfer length for each endpoint.
+    ///
+    /// Zero indicates that the endpoint uses single-packet transfers.
+    max_transfer_lens: [usize; crate::state::MAX_ENDPOINTS],
+    /// Set when the PHY is in low-power mode, while the bus is suspended.
+    suspended: bool,
+    /// The timer that ends remote wakeup signaling, if the driver is
+    /// signaling remote wakeup.
+    remote_wakeup: Option<gpt::Instance>,
+    /// Set when the user wants the device on the bus.
+    ///
+    /// When VBUS detection is enabled, the driver only attaches when this
+    /// is set and VBUS is present.
+    attach: bool,
+    vbus: Option<VbusDetection>,
+    /// The USB analog VBUS detect status register, if the user supplied it.
+    vbus_detect_stat: Option<VbusDetectStat>,
+    force_reset: Option<ForceReset>,
+    /// The speed negotiated during the most recent reset.
+    speed: Option<BusSpeed>,
+    /// Set by poll() when the controller receives a start-of-frame (SOF).
+    sof: bool,
+    /// Set when the user enables SOF interrupts.
+    ///
+    /// Deferred isochronous primes also enable SOF interrupts.
+    sof_interrupts: bool,
+    /// Recover non-zero endpoints when a read or write finds a failed transfer.
+    auto_recover: bool,
+    /// Endpoints with NAK interrupts, laid out like ENDPTNAKEN.
+    nak_enabled: u32,
+    /// NAKs that poll() observed, but the user hasn't taken, laid out
+    /// like ENDPTNAK.
+    nak: u32,
 }
 
+/// How long the device drives resume signaling for a remote wakeup
+///
+/// The USB specification requires 1ms to 15ms.
+const REMOTE_WAKEUP_SIGNALING_US: u32 = 10_000;
+
 impl Driver {
     /// Create a new `Driver`
     ///
@@ -72,21 +304,37 @@ impl Driver {
     ///
     /// Panics if the endpoint bufer or state has already been assigned to another USB
     /// driver.
-    pub fn new<const N: u8, const SIZE: usize, const EP_COUNT: usize>(
+    pub fn new<const N: u8, const SIZE: usize, const EP_COUNT: usize, const TD_COUNT: usize>(
         instances: crate::Instances<N>,
         buffer: &'static crate::buffer::EndpointMemory<SIZE>,
-        state: &'static crate::state::EndpointState<EP_COUNT>,
+        state: &'static crate::state::EndpointState<EP_COUNT, TD_COUNT>,
     ) -> Self {
         let ral::ErasedInstances { usb, usbphy: phy } = ral::erase_instances(instances);
         let ep_allocator = state.allocator().expect("Endpoint state already assigned");
         Driver {
             usb,
             phy,
-            buffer_allocator: buffer
-                .allocator()
-                .expect("Endpoint memory already assigned"),
+            buffers: buffer::Regions::new(
+                buffer
+                    .allocator()
+                    .expect("Endpoint memory already assigned"),
+            ),
             ep_allocator,
             ep_out: 0,
+            ep_out_pending: 0,
+            max_transfer_lens: [0; crate::state::MAX_ENDPOINTS],
+            suspended: false,
+            remote_wakeup: None,
+            attach: false,
+            vbus: None,
+            vbus_detect_stat: None,
+            force_reset: None,
+            speed: None,
+            sof: false,
+            sof_interrupts: false,
+            auto_recover: false,
+            nak_enabled: 0,
+            nak: 0,
         }
     }
 
@@ -142,9 +390,9 @@ impl Driver {
     pub fn set_interrupts(&mut self, interrupts: bool) {
         if interrupts {
             // Keep this in sync with the poll() behaviors
-            ral::modify_reg!(ral::usb, self.usb, USBINTR, UE: 1, URE: 1);
+            ral::modify_reg!(ral::usb, self.usb, USBINTR, UE: 1, URE: 1, SLE: 1, PCE: 1);
         } else {
-            ral::modify_reg!(ral::usb, self.usb, USBINTR, UE: 0, URE: 0);
+            ral::modify_reg!(ral::usb, self.usb, USBINTR, UE: 0, URE: 0, SLE: 0, PCE: 0);
         }
     }
 
@@ -161,22 +409,348 @@ impl Driver {
         debug!("ADDRESS {=u8}", address);
     }
 
+    /// Attach to the bus
+    ///
+    /// If VBUS detection is enabled, the driver waits for VBUS before it
+    /// attaches.
     pub fn attach(&mut self) {
-        ral::modify_reg!(ral::usb, self.usb, USBCMD, RS: 1);
+        self.attach = true;
+        if self.vbus.as_ref().is_none_or(|vbus| vbus.attached) {
+            ral::modify_reg!(ral::usb, self.usb, USBCMD, RS: 1);
+        }
     }
 
-    pub fn bus_reset(&mut self) {
-        ral::modify_reg!(ral::usb, self.usb, ENDPTSTAT, |endptstat| endptstat);
+    /// Stop the controller, and remove the pull-up from the bus
+    ///
+    /// Cancels all transfers, and reinitializes all endpoints. The device
+    /// is no longer configured.
+    pub fn detach(&mut self) {
+        self.attach = false;
+        if let Some(force_reset) = &mut self.force_reset {
+            force_reset.pending = false;
+        }
+        self.disconnect();
+    }
 
-        ral::modify_reg!(ral::usb, self.usb, ENDPTCOMPLETE, |endptcomplete| {
-            endptcomplete
+    /// Stop the controller, cancel all transfers, and reinitialize all endpoints
+    ///
+    /// Unlike [`detach()`](Driver::detach), this keeps the user's request to attach.
+    fn disconnect(&mut self) {
+        self.end_remote_wakeup();
+        self.cancel_transfers();
+        ral::modify_reg!(ral::usb, self.usb, USBCMD, RS: 0);
+        self.initialize_endpoints();
+        self.speed = None;
+        debug!("DETACHED");
+    }
+
+    /// Configure the timer, and the detach duration, for [`force_reset()`](Driver::force_reset)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` debounces VBUS, or if it times remote wakeup signaling.
+    pub fn set_force_reset_timer(&mut self, timer: gpt::Instance, detach_us: u32) {
+        assert!(
+            self.vbus.as_ref().is_none_or(|vbus| vbus.timer != timer)
+                && self.remote_wakeup != Some(timer),
+            "GPT already in use"
+        );
+        self.gpt_mut(timer, |gpt| {
+            gpt.stop();
+            gpt.clear_elapsed();
+            gpt.set_mode(gpt::Mode::OneShot);
+            gpt.set_load(detach_us);
+            gpt.set_interrupt_enabled(true);
         });
-        ral::modify_reg!(ral::usb, self.usb, ENDPTNAK, |endptnak| endptnak);
-        ral::write_reg!(ral::usb, self.usb, ENDPTNAKEN, 0);
+        self.force_reset = Some(ForceReset {
+            timer,
+            detach_us,
+            pending: false,
+        });
+    }
+
+    /// Detach from the bus, then re-attach once the force reset timer elapses
+    ///
+    /// [`poll()`](Driver::poll) re-attaches the driver. Returns `Unsupported` if
+    /// there's no force reset timer.
+    pub fn force_reset(&mut self) -> Result<(), UsbError> {
+        let Some(ForceReset {
+            timer, detach_us, ..
+        }) = self.force_reset
+        else {
+            return Err(UsbError::Unsupported);
+        };
+
+        self.detach();
+        self.gpt_mut(timer, |gpt| {
+            gpt.stop();
+            gpt.clear_elapsed();
+            gpt.set_load(detach_us);
+            gpt.reset();
+            gpt.run();
+        });
+        if let Some(force_reset) = &mut self.force_reset {
+            force_reset.pending = true;
+        }
+        Ok(())
+    }
+
+    /// Re-attach after a force reset, once the timer elapses
+    fn poll_force_reset(&mut self) {
+        let Some(ForceReset {
+            timer,
+            pending: true,
+            ..
+        }) = self.force_reset
+        else {
+            return;
+        };
+        if self.gpt_mut(timer, |gpt| gpt.is_elapsed()) {
+            self.gpt_mut(timer, |gpt| gpt.clear_elapsed());
+            if let Some(force_reset) = &mut self.force_reset {
+                force_reset.pending = false;
+            }
+            self.attach();
+        }
+    }
+
+    /// Put the PHY into low-power mode while the bus is suspended
+    ///
+    /// Stops the PHY clock, and powers down the PHY's transmitters and
+    /// receivers. A resume or reset on the bus powers the PHY back on.
+    /// Call [`resume()`](Driver::resume) to restore the PHY once the bus
+    /// resumes.
+    ///
+    /// Does nothing if the PHY is already in low-power mode.
+    pub fn suspend(&mut self) {
+        if self.suspended {
+            return;
+        }
+        ral::write_reg!(ral::usbphy, self.phy, CTRL_SET, ENAUTOCLR_PHY_PWD: 1);
+        ral::modify_reg!(ral::usb, self.usb, PORTSC1, PHCD: 1);
+        ral::write_reg!(
+            ral::usbphy,
+            self.phy,
+            PWD_SET,
+            TXPWDFS: 1,
+            TXPWDIBIAS: 1,
+            TXPWDV2I: 1,
+            RXPWDENV: 1,
+            RXPWD1PT1: 1,
+            RXPWDDIFF: 1,
+            RXPWDRX: 1
+        );
+        self.suspended = true;
+        debug!("SUSPEND");
+    }
+
+    /// Restore the PHY after a suspend
+    ///
+    /// Does nothing if the PHY isn't in low-power mode.
+    pub fn resume(&mut self) {
+        if !self.suspended {
+            return;
+        }
+        ral::write_reg!(
+            ral::usbphy,
+            self.phy,
+            PWD_CLR,
+            TXPWDFS: 1,
+            TXPWDIBIAS: 1,
+            TXPWDV2I: 1,
+            RXPWDENV: 1,
+            RXPWD1PT1: 1,
+            RXPWDDIFF: 1,
+            RXPWDRX: 1
+        );
+        ral::modify_reg!(ral::usb, self.usb, PORTSC1, PHCD: 0);
+        self.suspended = false;
+        debug!("RESUME");
+    }
+
+    /// Monitor VBUS, and automatically attach and detach
+    ///
+    /// `timer` debounces VBUS changes. The driver samples the session state
+    /// now, and detaches if VBUS is absent.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` times force resets, or remote wakeup signaling.
+    pub fn enable_vbus_detection(&mut self, timer: gpt::Instance) {
+        assert!(
+            self.force_reset.as_ref().is_none_or(|fr| fr.timer != timer)
+                && self.remote_wakeup != Some(timer),
+            "GPT already in use"
+        );
+        ral::modify_reg!(ral::usb, self.usb, OTGSC, |otgsc| (otgsc & !OTGSC_STATUS)
+            | ral::usb::OTGSC::BSVIE::mask);
+        // Clear any stale session change.
+        self.clear_otgsc_status(ral::usb::OTGSC::BSVIS::mask);
+        self.gpt_mut(timer, |gpt| {
+            gpt.stop();
+            gpt.clear_elapsed();
+            gpt.set_mode(gpt::Mode::OneShot);
+            gpt.set_load(VBUS_DEBOUNCE_US);
+            gpt.set_interrupt_enabled(true);
+        });
+
+        let attached = self.vbus_valid();
+        if !attached {
+            ral::modify_reg!(ral::usb, self.usb, USBCMD, RS: 0);
+        }
+        self.vbus = Some(VbusDetection {
+            timer,
+            attached,
+            event: None,
+        });
+    }
+
+    /// Also require the USB analog VBUS detector's `VBUS_VALID` status
+    ///
+    /// `status` is this USB instance's VBUS detect status register.
+    pub fn set_vbus_detect_status(&mut self, status: &'static ral::RORegister<u32>) {
+        self.vbus_detect_stat = Some(VbusDetectStat(status));
+    }
+
+    /// Indicates if the B session is valid, and if the USB analog VBUS
+    /// detector, when supplied, reports valid VBUS
+    fn vbus_valid(&self) -> bool {
+        ral::read_reg!(ral::usb, self.usb, OTGSC, BSV == 1)
+            && self
+                .vbus_detect_stat
+                .as_ref()
+                .is_none_or(VbusDetectStat::is_vbus_valid)
+    }
+
+    /// Clear the OTGSC interrupt status bits in `status`
+    ///
+    /// Writes zero to the other status bits, so they stay set.
+    fn clear_otgsc_status(&mut self, status: u32) {
+        ral::modify_reg!(ral::usb, self.usb, OTGSC, |otgsc| (otgsc & !OTGSC_STATUS)
+            | status);
+    }
+
+    /// Indicates if VBUS is present
+    ///
+    /// Returns `None` if VBUS detection isn't enabled.
+    pub fn is_vbus_present(&self) -> Option<bool> {
+        self.vbus.as_ref().map(|vbus| vbus.attached)
+    }
+
+    /// Take the most recent VBUS session change
+    pub fn take_vbus_event(&mut self) -> Option<VbusEvent> {
+        self.vbus.as_mut()?.event.take()
+    }
+
+    /// Debounce VBUS, and attach or detach when the session changes
+    fn poll_vbus(&mut self) -> Option<VbusEvent> {
+        let timer = self.vbus.as_ref()?.timer;
+
+        if ral::read_reg!(ral::usb, self.usb, OTGSC, BSVIS == 1) {
+            self.clear_otgsc_status(ral::usb::OTGSC::BSVIS::mask);
+            self.gpt_mut(timer, |gpt| {
+                gpt.stop();
+                gpt.clear_elapsed();
+                gpt.reset();
+                gpt.run();
+            });
+            return None;
+        }
+
+        if !self.gpt_mut(timer, |gpt| gpt.is_elapsed()) {
+            return None;
+        }
+        self.gpt_mut(timer, |gpt| gpt.clear_elapsed());
+
+        let present = self.vbus_valid();
+        let vbus = self.vbus.as_mut().unwrap();
+        if present == vbus.attached {
+            return None;
+        }
+        vbus.attached = present;
+
+        let event = if present {
+            VbusEvent::Attached
+        } else {
+            VbusEvent::Detached
+        };
+        vbus.event = Some(event);
 
-        while ral::read_reg!(ral::usb, self.usb, ENDPTPRIME) != 0 {}
-        ral::write_reg!(ral::usb, self.usb, ENDPTFLUSH, u32::MAX);
-        while ral::read_reg!(ral::usb, self.usb, ENDPTFLUSH) != 0 {}
+        if present {
+            self.resume();
+            if self.attach {
+                ral::modify_reg!(ral::usb, self.usb, USBCMD, RS: 1);
+            }
+            debug!("ATTACHED");
+        } else {
+            self.disconnect();
+        }
+        Some(event)
+    }
+
+    /// Start remote wakeup signaling
+    ///
+    /// Restores the PHY, then drives resume signaling on the bus. `timer`
+    /// times the signaling, and its elapsed interrupt causes a USB interrupt.
+    /// [`poll()`](Driver::poll) ends the signaling, and reports a resume, once
+    /// the timer elapses.
+    ///
+    /// Returns `InvalidState` if the bus isn't suspended, if the driver
+    /// is already signaling remote wakeup, or if `timer` debounces VBUS or
+    /// times force resets.
+    pub fn remote_wakeup(&mut self, timer: gpt::Instance) -> Result<(), UsbError> {
+        if self.remote_wakeup.is_some()
+            || self.is_timer_used(timer)
+            || !self.suspended
+            || ral::read_reg!(ral::usb, self.usb, PORTSC1, SUSP == 0)
+        {
+            return Err(UsbError::InvalidState);
+        }
+
+        self.resume();
+        ral::modify_reg!(ral::usb, self.usb, PORTSC1, FPR: 1);
+        self.gpt_mut(timer, |gpt| {
+            gpt.stop();
+            gpt.clear_elapsed();
+            gpt.set_interrupt_enabled(true);
+            gpt.set_mode(gpt::Mode::OneShot);
+            gpt.set_load(REMOTE_WAKEUP_SIGNALING_US);
+            gpt.reset();
+            gpt.run();
+        });
+        self.remote_wakeup = Some(timer);
+        debug!("REMOTE WAKEUP");
+        Ok(())
+    }
+
+    /// Indicates if VBUS detection, force resets, or remote wakeup signaling use `timer`
+    pub fn is_timer_used(&self, timer: gpt::Instance) -> bool {
+        self.vbus.as_ref().is_some_and(|vbus| vbus.timer == timer)
+            || self
+                .force_reset
+                .as_ref()
+                .is_some_and(|fr| fr.timer == timer)
+            || self.remote_wakeup == Some(timer)
+    }
+
+    /// Stop remote wakeup signaling, and release the timer
+    fn end_remote_wakeup(&mut self) {
+        if let Some(timer) = self.remote_wakeup.take() {
+            ral::modify_reg!(ral::usb, self.usb, PORTSC1, FPR: 0);
+            self.gpt_mut(timer, |gpt| {
+                gpt.stop();
+                gpt.clear_elapsed();
+                gpt.set_interrupt_enabled(false);
+            });
+        }
+    }
+
+    pub fn bus_reset(&mut self) {
+        // A reset can end a suspend, or a remote wakeup.
+        self.end_remote_wakeup();
+        self.resume();
+
+        self.cancel_transfers();
 
         debug_assert!(
             ral::read_reg!(ral::usb, self.usb, PORTSC1, PR == 1),
@@ -184,9 +758,212 @@ impl Driver {
         );
         debug!("RESET");
 
+        // The port change at the end of the reset reports the new speed.
+        self.speed = None;
         self.initialize_endpoints();
     }
 
+    /// Enable (`true`) or disable (`false`) start-of-frame (SOF) interrupts
+    ///
+    /// The controller receives a SOF every millisecond at full speed, and
+    /// every microframe (125us) at high speed.
+    pub fn set_sof_interrupts(&mut self, interrupts: bool) {
+        self.sof_interrupts = interrupts;
+        let deferred = self
+            .ep_allocator
+            .nonzero_endpoints_iter_mut()
+            .any(|ep| ep.is_prime_deferred());
+        ral::modify_reg!(ral::usb, self.usb, USBINTR, SRE: (interrupts || deferred) as u32);
+    }
+
+    /// Enable (`true`) or disable (`false`) NAK interrupts for an endpoint
+    ///
+    /// Returns `InvalidEndpoint` if the endpoint isn't allocated.
+    pub fn set_nak_interrupts(
+        &mut self,
+        addr: EndpointAddress,
+        interrupts: bool,
+    ) -> Result<(), UsbError> {
+        let bit = self
+            .ep_allocator
+            .endpoint(addr)
+            .ok_or(UsbError::InvalidEndpoint)?
+            .register_bit();
+        if interrupts {
+            self.nak_enabled |= bit;
+        } else {
+            self.nak_enabled &= !bit;
+            self.nak &= !bit;
+        }
+        self.write_nak_enabled();
+        Ok(())
+    }
+
+    /// Apply the NAK interrupt enables to the controller
+    fn write_nak_enabled(&mut self) {
+        ral::write_reg!(ral::usb, self.usb, ENDPTNAKEN, self.nak_enabled);
+        ral::modify_reg!(ral::usb, self.usb, USBINTR, NAKE: (self.nak_enabled != 0) as u32);
+    }
+
+    /// Take the endpoints that NAKed the host since the last call
+    pub fn take_nak_events(&mut self) -> NakEvents {
+        let nak = core::mem::take(&mut self.nak);
+        NakEvents {
+            ep_in: (nak >> 16) as u16,
+            ep_out: nak as u16,
+        }
+    }
+
+    /// Indicates if the controller received a SOF since the last call
+    pub fn take_sof(&mut self) -> bool {
+        core::mem::take(&mut self.sof)
+    }
+
+    /// Returns the current 11-bit frame number
+    pub fn frame_number(&self) -> u16 {
+        (ral::read_reg!(ral::usb, self.usb, FRINDEX) >> 3) as u16 & 0x7FF
+    }
+
+    /// Returns the current microframe, 0 through 7
+    ///
+    /// Always zero at low and full speed.
+    pub fn microframe(&self) -> u8 {
+        ral::read_reg!(ral::usb, self.usb, FRINDEX) as u8 & 0x7
+    }
+
+    /// Returns the (micro)frame of an isochronous endpoint's first transfer
+    ///
+    /// Returns `None` if the endpoint isn't allocated, or if it wasn't primed
+    /// at a (micro)frame boundary.
+    pub fn isochronous_start_frame(&self, addr: EndpointAddress) -> Option<Frame> {
+        let frindex = self.ep_allocator.endpoint(addr)?.prime_frindex()?;
+        // FRINDEX counts microframes. At low and full speed, it counts frames
+        // in bits 13:3.
+        let start = if self.speed == Some(BusSpeed::High) {
+            frindex + 1
+        } else {
+            (frindex | 0x7) + 1
+        };
+        Some(Frame {
+            number: (start >> 3) as u16 & 0x7FF,
+            microframe: start as u8 & 0x7,
+        })
+    }
+
+    /// Returns the bus speed negotiated during the most recent reset
+    ///
+    /// Returns `None` if the driver is detached, or if a reset is in progress.
+    pub fn negotiated_speed(&self) -> Option<BusSpeed> {
+        self.speed
+    }
+
+    /// Record the negotiated speed, once a reset finishes
+    fn update_speed(&mut self) {
+        if ral::read_reg!(ral::usb, self.usb, PORTSC1, PR == 1) {
+            return;
+        }
+        let speed = match ral::read_reg!(ral::usb, self.usb, PORTSC1, PSPD) {
+            1 => BusSpeed::Low,
+            2 => BusSpeed::High,
+            _ => BusSpeed::Full,
+        };
+        if self.speed != Some(speed) {
+            self.speed = Some(speed);
+            debug!("SPEED {=u8}", speed as u8);
+        }
+    }
+
+    /// Cancel all primed transfers, and clear endpoint status
+    fn cancel_transfers(&mut self) {
+        ral::modify_reg!(ral::usb, self.usb, ENDPTSTAT, |endptstat| endptstat);
+
+        ral::modify_reg!(ral::usb, self.usb, ENDPTCOMPLETE, |endptcomplete| {
+            endptcomplete
+        });
+        ral::modify_reg!(ral::usb, self.usb, ENDPTNAK, |endptnak| endptnak);
+        self.nak_enabled = 0;
+        self.nak = 0;
+        self.write_nak_enabled();
+
+        // Rather than wait for primes to finish, make sure that any prime that's
+        // still in progress finds no transfers.
+        for ep in self.ep_allocator.endpoints_iter_mut() {
+            ep.terminate();
+        }
+        ral::flush(&self.usb, u32::MAX);
+
+        // A prime that the controller accepted before the flush can still finish
+        // after it. Flush those endpoints again, until none is primed.
+        loop {
+            let primed = ral::read_reg!(ral::usb, self.usb, ENDPTPRIME)
+                | ral::read_reg!(ral::usb, self.usb, ENDPTSTAT);
+            if primed == 0 {
+                break;
+            }
+            ral::flush(&self.usb, primed);
+        }
+    }
+
+    /// Returns the endpoint memory and endpoint state usage
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let total = self.buffers.iter().map(buffer::Allocator::total).sum();
+        let used = self.buffers.iter().map(buffer::Allocator::used).sum();
+        MemoryUsage {
+            total,
+            used,
+            free: total - used,
+            high_water_mark: self
+                .buffers
+                .iter()
+                .map(buffer::Allocator::high_water_mark)
+                .sum(),
+            endpoint_capacity: self.ep_allocator.capacity(),
+            endpoints_allocated: self.endpoint_usage().iter().flatten().count(),
+            endpoints_required: self.ep_allocator.required_count(),
+        }
+    }
+
+    /// Describe each allocated endpoint, indexed by its endpoint state index
+    pub fn endpoint_usage(&self) -> [Option<EndpointUsage>; crate::state::MAX_ENDPOINTS] {
+        let mut usage = [None; crate::state::MAX_ENDPOINTS];
+        for (state_index, usage) in usage.iter_mut().enumerate() {
+            let direction = if state_index % 2 == 0 {
+                UsbDirection::Out
+            } else {
+                UsbDirection::In
+            };
+            let address = EndpointAddress::from_parts(state_index / 2, direction);
+            *usage = self.ep_allocator.endpoint(address).map(|ep| EndpointUsage {
+                address,
+                kind: ep.kind(),
+                buffer_address: ep.buffer().as_ptr() as usize,
+                buffer_len: ep.buffer().len(),
+                reserved: ep.buffer().reserved(),
+                region: self.buffers.region_of(ep.buffer()).unwrap_or(0),
+                state_index,
+            });
+        }
+        usage
+    }
+
+    /// Add a region of endpoint memory, returning the region's index
+    ///
+    /// Returns `None` if there's no room for another region.
+    pub fn add_endpoint_region(
+        &mut self,
+        allocator: buffer::Allocator,
+        placement: buffer::Placement,
+    ) -> Option<usize> {
+        let region = self.buffers.add(allocator, placement)?;
+        debug!("ADD REGION {=usize}", region);
+        Some(region)
+    }
+
+    /// Indicates if there's room for another endpoint memory region
+    pub fn has_endpoint_region_room(&self) -> bool {
+        !self.buffers.is_full()
+    }
+
     /// Check if the endpoint is valid
     pub fn is_allocated(&self, addr: EndpointAddress) -> bool {
         self.ep_allocator.endpoint(addr).is_some()
@@ -198,36 +975,66 @@ impl Driver {
     ///
     /// Panics if EP0 OUT isn't allocated.
     pub fn ctrl0_read(&mut self, buffer: &mut [u8]) -> Result<usize, UsbError> {
+        if buffer.len() >= 8
+            && let Some(setup) = self.ctrl0_setup()
+        {
+            buffer[..8].copy_from_slice(&setup);
+            return Ok(8);
+        }
+
+        self.ctrl0_read_data(buffer)
+    }
+
+    /// Read a data buffer from EP0 OUT, ignoring any setup packet
+    ///
+    /// # Panics
+    ///
+    /// Panics if EP0 OUT isn't allocated.
+    pub fn ctrl0_read_data(&mut self, buffer: &mut [u8]) -> Result<usize, UsbError> {
         let ctrl_out = self.ep_allocator.endpoint_mut(ctrl_ep0_out()).unwrap();
-        if ctrl_out.has_setup(&self.usb) && buffer.len() >= 8 {
-            debug!("EP0 Out SETUP");
-            let setup = ctrl_out.read_setup(&self.usb);
-            buffer[..8].copy_from_slice(&setup.to_le_bytes());
-
-            if !ctrl_out.is_primed(&self.usb) {
-                ctrl_out.clear_nack(&self.usb);
-                let max_packet_len = ctrl_out.max_packet_len();
-                ctrl_out.schedule_transfer(&self.usb, max_packet_len);
-            }
+        ctrl_out.check_errors()?;
 
-            Ok(8)
-        } else {
-            ctrl_out.check_errors()?;
+        if ctrl_out.is_primed(&self.usb) {
+            return Err(UsbError::WouldBlock);
+        }
 
-            if ctrl_out.is_primed(&self.usb) {
-                return Err(UsbError::WouldBlock);
-            }
+        ctrl_out.clear_complete(&self.usb);
+        ctrl_out.clear_nack(&self.usb);
 
-            ctrl_out.clear_complete(&self.usb);
-            ctrl_out.clear_nack(&self.usb);
+        let read = ctrl_out.read(buffer);
+        debug!("EP0 Out {=usize}", read);
+        ctrl_out.reset_transfers();
+        let max_packet_len = ctrl_out.max_packet_len();
+        ctrl_out.schedule_transfer(&self.usb, max_packet_len);
+
+        Ok(read)
+    }
+
+    /// Read a setup packet from EP0 OUT
+    ///
+    /// Returns `None` if there's no setup packet. Otherwise, this prepares
+    /// EP0 OUT for a data stage.
+    ///
+    /// # Panics
+    ///
+    /// Panics if EP0 OUT isn't allocated.
+    pub fn ctrl0_setup(&mut self) -> Option<[u8; 8]> {
+        let ctrl_out = self.ep_allocator.endpoint_mut(ctrl_ep0_out()).unwrap();
+        if !ctrl_out.has_setup(&self.usb) {
+            return None;
+        }
+
+        debug!("EP0 Out SETUP");
+        let setup = ctrl_out.read_setup(&self.usb);
 
-            let read = ctrl_out.read(buffer);
-            debug!("EP0 Out {=usize}", read);
+        if !ctrl_out.is_primed(&self.usb) {
+            ctrl_out.clear_nack(&self.usb);
+            ctrl_out.reset_transfers();
             let max_packet_len = ctrl_out.max_packet_len();
             ctrl_out.schedule_transfer(&self.usb, max_packet_len);
-
-            Ok(read)
         }
+
+        Some(setup.to_le_bytes())
     }
 
     /// Write to the host from EP0 IN
@@ -247,6 +1054,7 @@ impl Driver {
         }
 
         ctrl_in.clear_nack(&self.usb);
+        ctrl_in.reset_transfers();
 
         let written = ctrl_in.write(buffer);
         ctrl_in.schedule_transfer(&self.usb, written);
@@ -256,6 +1064,7 @@ impl Driver {
         if !ctrl_out.is_primed(&self.usb) {
             ctrl_out.clear_complete(&self.usb);
             ctrl_out.clear_nack(&self.usb);
+            ctrl_out.reset_transfers();
             ctrl_out.schedule_transfer(&self.usb, 0);
         }
 
@@ -264,42 +1073,156 @@ impl Driver {
 
     /// Read data from an endpoint, and schedule the next transfer
     ///
+    /// Reads the oldest completed transfer. The endpoint's TD is then
+    /// re-scheduled at the end of the endpoint's queue.
+    ///
     /// # Panics
     ///
     /// Panics if the endpoint isn't allocated.
     pub fn ep_read(&mut self, buffer: &mut [u8], addr: EndpointAddress) -> Result<usize, UsbError> {
+        self.completed_ep_out(addr)?;
         let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
-        debug!("EP{=usize} Out", ep.address().index());
-        ep.check_errors()?;
-
-        if ep.is_primed(&self.usb) || (self.ep_out & (1 << ep.address().index()) == 0) {
-            return Err(UsbError::WouldBlock);
-        }
-
-        ep.clear_complete(&self.usb); // Clears self.ep_out bit on the next poll() call...
-        ep.clear_nack(&self.usb);
-
         let read = ep.read(buffer);
+        ep.retire();
 
-        let max_packet_len = ep.max_packet_len();
-        ep.schedule_transfer(&self.usb, max_packet_len);
+        let transfer_len = ep.transfer_len();
+        ep.schedule_transfer(&self.usb, transfer_len);
+        self.update_ep_out_pending(addr);
 
         Ok(read)
     }
 
-    /// Write data to an endpoint
+    /// Lend an endpoint's transfer buffer
     ///
-    /// # Panics
+    /// For an OUT endpoint, this is like [`ep_read`](Driver::ep_read), but without
+    /// a copy. The endpoint keeps the transfer, and doesn't receive into its buffer,
+    /// until you [`ep_release`](Driver::ep_release) it.
     ///
-    /// Panics if the endpoint isn't allocated.
-    pub fn ep_write(&mut self, buffer: &[u8], addr: EndpointAddress) -> Result<usize, UsbError> {
-        let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
-        ep.check_errors()?;
-
-        if ep.is_primed(&self.usb) {
-            return Err(UsbError::WouldBlock);
+    /// For an IN endpoint, this lends the buffer of the next transfer. Send the
+    /// transfer with [`ep_commit`](Driver::ep_commit). Returns `WouldBlock` if every
+    /// TD has a scheduled transfer.
+    ///
+    /// Returns `WouldBlock` if the endpoint already lent a buffer, or `InvalidEndpoint`
+    /// if the endpoint isn't a non-zero, allocated endpoint.
+    pub fn ep_lend(&mut self, addr: EndpointAddress) -> Result<buffer::Buffer, UsbError> {
+        if addr.index() == 0 || !self.is_allocated(addr) {
+            return Err(UsbError::InvalidEndpoint);
         }
-
+        if addr.direction() == UsbDirection::Out {
+            self.completed_ep_out(addr)?;
+        }
+        if addr.direction() == UsbDirection::In {
+            self.check_ep_errors(addr)?;
+        }
+        let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
+        if addr.direction() == UsbDirection::In {
+            ep.retire_completed();
+        }
+        ep.lend().ok_or(UsbError::WouldBlock)
+    }
+
+    /// Release a lent buffer
+    ///
+    /// For an OUT endpoint, this schedules the next receive. A disabled endpoint
+    /// is primed once it's enabled. For an IN endpoint, this drops the transfer
+    /// without sending it.
+    ///
+    /// Does nothing if the endpoint isn't allocated, or if it didn't lend a buffer.
+    pub fn ep_release(&mut self, addr: EndpointAddress) {
+        let Some(ep) = self.ep_allocator.endpoint_mut(addr) else {
+            return;
+        };
+        if !ep.release() || addr.direction() == UsbDirection::In {
+            return;
+        }
+        if ep.is_enabled(&self.usb) {
+            let transfer_len = ep.transfer_len();
+            ep.schedule_transfer(&self.usb, transfer_len);
+        }
+        self.update_ep_out_pending(addr);
+    }
+
+    /// Send `len` bytes from an IN endpoint's lent buffer
+    ///
+    /// Returns the number of bytes scheduled, which is constrained by the transfer
+    /// length. Returns `InvalidState` if the endpoint didn't lend a buffer, if it's
+    /// disabled or stalled, or if its oldest transfer failed. After an error, the
+    /// endpoint still lends the buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint isn't allocated.
+    pub fn ep_commit(&mut self, addr: EndpointAddress, len: usize) -> Result<usize, UsbError> {
+        self.check_ep_errors(addr)?;
+        let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
+        if !ep.is_lent() || !ep.is_enabled(&self.usb) || ep.is_stalled(&self.usb) {
+            return Err(UsbError::InvalidState);
+        }
+        ep.clear_nack(&self.usb);
+        ep.commit(&self.usb, len).ok_or(UsbError::InvalidState)
+    }
+
+    /// Prepare to take the oldest completed transfer from an OUT endpoint
+    ///
+    /// Returns `WouldBlock` if there's no completed transfer that `poll()`
+    /// signaled, or if the endpoint lent its transfer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint isn't allocated.
+    fn completed_ep_out(&mut self, addr: EndpointAddress) -> Result<(), UsbError> {
+        let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
+        debug!("EP{=usize} Out", ep.address().index());
+        if ep.is_lent() {
+            return Err(UsbError::WouldBlock);
+        }
+        self.check_ep_errors(addr)?;
+
+        let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
+
+        // Drop failed isochronous transfers, and receive again.
+        if ep.retire_failed() {
+            ep.schedule_receives(&self.usb);
+        }
+
+        let mask = 1 << ep.address().index();
+        if !ep.is_complete() || (self.ep_out & mask == 0) {
+            return Err(UsbError::WouldBlock);
+        }
+
+        ep.clear_complete(&self.usb); // Clears self.ep_out bit on the next poll() call...
+        ep.clear_nack(&self.usb);
+        Ok(())
+    }
+
+    /// Keep signaling an OUT endpoint that still holds completed transfers
+    fn update_ep_out_pending(&mut self, addr: EndpointAddress) {
+        let mask = 1 << addr.index();
+        let pending = self
+            .ep_allocator
+            .endpoint(addr)
+            .is_some_and(|ep| !ep.is_lent() && ep.is_complete());
+        if pending {
+            self.ep_out_pending |= mask;
+        } else {
+            self.ep_out_pending &= !mask;
+        }
+    }
+
+    /// Write data to an endpoint
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint isn't allocated.
+    pub fn ep_write(&mut self, buffer: &[u8], addr: EndpointAddress) -> Result<usize, UsbError> {
+        self.check_ep_errors(addr)?;
+        let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
+
+        ep.retire_completed();
+        if ep.is_full() || ep.is_lent() {
+            return Err(UsbError::WouldBlock);
+        }
+
         ep.clear_nack(&self.usb);
 
         let written = ep.write(buffer);
@@ -308,19 +1231,137 @@ impl Driver {
         Ok(written)
     }
 
+    /// Check an endpoint's oldest transfer for errors
+    ///
+    /// If automatic recovery is on, this recovers a non-zero endpoint after
+    /// a failed transfer. The error is still returned.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint isn't allocated.
+    fn check_ep_errors(&mut self, addr: EndpointAddress) -> Result<(), UsbError> {
+        let result = self.ep_allocator.endpoint_mut(addr).unwrap().check_errors();
+        if result.is_err() && self.auto_recover && addr.index() != 0 {
+            self.recover_ep(addr, false)?;
+        }
+        result
+    }
+
+    /// Recover a non-zero endpoint from a failed transfer
+    ///
+    /// See [`Endpoint::recover`](crate::endpoint::Endpoint::recover). Returns
+    /// `InvalidEndpoint` if the endpoint is a control endpoint, or if it isn't
+    /// allocated.
+    pub fn recover_ep(
+        &mut self,
+        addr: EndpointAddress,
+        reset_data_toggle: bool,
+    ) -> Result<(), UsbError> {
+        if addr.index() == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let ep = self
+            .ep_allocator
+            .endpoint_mut(addr)
+            .ok_or(UsbError::InvalidEndpoint)?;
+        ep.recover(&self.usb, reset_data_toggle);
+        if addr.direction() == UsbDirection::Out {
+            self.update_ep_out_pending(addr);
+        }
+        debug!("RECOVER EP{=usize} {}", addr.index(), addr.direction());
+        Ok(())
+    }
+
+    /// Reset the data toggle of a non-zero endpoint
+    ///
+    /// Returns `InvalidEndpoint` if the endpoint is a control endpoint, or if
+    /// it isn't allocated.
+    pub fn reset_ep_data_toggle(&mut self, addr: EndpointAddress) -> Result<(), UsbError> {
+        if addr.index() == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.ep_allocator
+            .endpoint_mut(addr)
+            .ok_or(UsbError::InvalidEndpoint)?
+            .reset_data_toggle(&self.usb);
+        Ok(())
+    }
+
+    /// Cancel the scheduled transfers of a non-zero endpoint
+    ///
+    /// See [`Endpoint::cancel`](crate::endpoint::Endpoint::cancel). Returns the
+    /// number of bytes that the cancelled transfers already moved. Returns
+    /// `InvalidEndpoint` if the endpoint is a control endpoint, or if it isn't
+    /// allocated.
+    pub fn cancel_ep(&mut self, addr: EndpointAddress) -> Result<usize, UsbError> {
+        if addr.index() == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let ep = self
+            .ep_allocator
+            .endpoint_mut(addr)
+            .ok_or(UsbError::InvalidEndpoint)?;
+        let moved = ep.cancel(&self.usb);
+        if addr.direction() == UsbDirection::Out {
+            self.update_ep_out_pending(addr);
+        }
+        debug!(
+            "CANCEL EP{=usize} {} MOVED {=usize}",
+            addr.index(),
+            addr.direction(),
+            moved
+        );
+        Ok(moved)
+    }
+
+    /// Recover endpoints when a read or write finds a failed transfer
+    pub fn set_auto_recovery(&mut self, auto_recover: bool) {
+        self.auto_recover = auto_recover;
+    }
+
+    /// Returns the status of an endpoint
+    ///
+    /// Returns `None` if the endpoint isn't allocated.
+    pub fn ep_status(&mut self, addr: EndpointAddress) -> Option<EndpointStatus> {
+        let ep = self.ep_allocator.endpoint_mut(addr)?;
+        ep.count_errors();
+        Some(EndpointStatus {
+            error: ep.transfer_error(),
+            error_counts: ep.error_counts(),
+            stalled: ep.is_stalled(&self.usb),
+            primed: ep.is_primed(&self.usb),
+        })
+    }
+
+    /// Take the most recent isochronous transfer error for an endpoint
+    ///
+    /// Returns `None` if the endpoint isn't allocated, or if there was no error
+    /// since the last call.
+    pub fn take_isochronous_error(&mut self, addr: EndpointAddress) -> Option<IsochronousError> {
+        self.ep_allocator
+            .endpoint_mut(addr)?
+            .take_isochronous_error()
+    }
+
     /// Stall an endpoint
     ///
+    /// Unstalling resets the data toggle, as required when the host clears
+    /// an endpoint halt.
+    ///
     /// # Panics
     ///
     /// Panics if the endpoint isn't allocated
     pub fn ep_stall(&mut self, stall: bool, addr: EndpointAddress) {
         let ep = self.ep_allocator.endpoint_mut(addr).unwrap();
         ep.set_stalled(&self.usb, stall);
+        if !stall {
+            ep.reset_data_toggle(&self.usb);
+        }
 
         // Re-prime any OUT endpoints if we're unstalling
         if !stall && addr.direction() == UsbDirection::Out && !ep.is_primed(&self.usb) {
-            let max_packet_len = ep.max_packet_len();
-            ep.schedule_transfer(&self.usb, max_packet_len);
+            ep.reset_transfers();
+            ep.schedule_receives(&self.usb);
         }
     }
 
@@ -336,9 +1377,120 @@ impl Driver {
             .is_stalled(&self.usb)
     }
 
+    /// Set the maximum transfer length for an endpoint that's not yet allocated
+    ///
+    /// Returns an error if the endpoint is a control endpoint, or if it's already
+    /// allocated. See the `BusAdapter` documentation for more information.
+    pub fn set_max_transfer_len(
+        &mut self,
+        addr: EndpointAddress,
+        max_transfer_len: usize,
+    ) -> Result<(), UsbError> {
+        if addr.index() == 0 || self.is_allocated(addr) {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let len = self
+            .max_transfer_lens
+            .get_mut(crate::state::index(addr))
+            .ok_or(UsbError::InvalidEndpoint)?;
+        *len = max_transfer_len.min(td::MAX_TRANSFER_LEN);
+        Ok(())
+    }
+
+    /// Returns the maximum transfer length of an allocated endpoint
+    pub fn max_transfer_len(&self, addr: EndpointAddress) -> Option<usize> {
+        self.ep_allocator.endpoint(addr).map(|ep| ep.transfer_len())
+    }
+
     /// Allocate a buffer from the endpoint memory
-    pub fn allocate_buffer(&mut self, max_packet_len: usize) -> Option<buffer::Buffer> {
-        self.buffer_allocator.allocate(max_packet_len)
+    ///
+    /// The endpoint's placement selects the endpoint memory region.
+    /// The buffer holds one transfer for each of the endpoint's transfer descriptors.
+    /// By default, a transfer is one packet. If the user set a larger transfer length
+    /// for this endpoint, each transfer holds that many bytes. Transfers longer than
+    /// [`td::MAX_UNALIGNED_TRANSFER_LEN`] are padded, so that every transfer starts on
+    /// a page boundary.
+    pub fn allocate_buffer(
+        &mut self,
+        addr: EndpointAddress,
+        max_packet_len: usize,
+        kind: EndpointType,
+    ) -> Option<buffer::Buffer> {
+        let tds = self.ep_allocator.transfer_descriptors(kind);
+        let transfer_len = self.requested_transfer_len(addr, max_packet_len, kind);
+        let (transfer_len, align) = if transfer_len > td::MAX_UNALIGNED_TRANSFER_LEN {
+            (
+                transfer_len.next_multiple_of(td::TRANSFER_ALIGNMENT),
+                td::TRANSFER_ALIGNMENT,
+            )
+        } else {
+            (transfer_len, buffer::CACHE_LINE_SIZE)
+        };
+        self.buffers
+            .allocate(addr, kind, transfer_len.checked_mul(tds)?, align)
+    }
+
+    /// Returns the transfer length for an endpoint that's being allocated
+    ///
+    /// The result is a multiple of the max packet length, and it's never smaller
+    /// than one packet. An isochronous transfer holds one (micro)frame of packets.
+    fn requested_transfer_len(
+        &self,
+        addr: EndpointAddress,
+        max_packet_len: usize,
+        kind: EndpointType,
+    ) -> usize {
+        if let EndpointType::Isochronous { .. } = kind {
+            let (max_packet_len, mult) = crate::endpoint::max_packet_parts(kind, max_packet_len);
+            return max_packet_len * mult;
+        }
+
+        let max_transfer_len = self
+            .max_transfer_lens
+            .get(crate::state::index(addr))
+            .copied()
+            .unwrap_or(0);
+        if max_packet_len == 0 || max_transfer_len <= max_packet_len {
+            max_packet_len
+        } else {
+            max_transfer_len / max_packet_len * max_packet_len
+        }
+    }
+
+    /// Allocate an endpoint, and its buffer
+    ///
+    /// If `addr` is `None`, this allocates the first free non-zero endpoint in
+    /// the direction `dir`.
+    pub fn alloc_ep(
+        &mut self,
+        dir: UsbDirection,
+        addr: Option<EndpointAddress>,
+        kind: EndpointType,
+        max_packet_len: usize,
+    ) -> Result<EndpointAddress, UsbError> {
+        if let Some(addr) = addr {
+            if self.is_allocated(addr) {
+                return Err(UsbError::InvalidEndpoint);
+            }
+            let buffer = self
+                .allocate_buffer(addr, max_packet_len, kind)
+                .ok_or(UsbError::EndpointMemoryOverflow)?;
+            self.allocate_ep(addr, buffer, max_packet_len, kind);
+            Ok(addr)
+        } else {
+            for idx in 1..8 {
+                let addr = EndpointAddress::from_parts(idx, dir);
+                if self.is_allocated(addr) {
+                    continue;
+                }
+                let buffer = self
+                    .allocate_buffer(addr, max_packet_len, kind)
+                    .ok_or(UsbError::EndpointMemoryOverflow)?;
+                self.allocate_ep(addr, buffer, max_packet_len, kind);
+                return Ok(addr);
+            }
+            Err(UsbError::EndpointOverflow)
+        }
     }
 
     /// Allocate a specific endpoint
@@ -350,11 +1502,17 @@ impl Driver {
         &mut self,
         addr: EndpointAddress,
         buffer: buffer::Buffer,
+        max_packet_len: usize,
         kind: EndpointType,
     ) {
-        self.ep_allocator
+        let transfer_len = self.requested_transfer_len(addr, max_packet_len, kind);
+        let ep = self
+            .ep_allocator
             .allocate_endpoint(addr, buffer, kind)
             .unwrap();
+        ep.set_max_packet_len(max_packet_len);
+        // The buffer may be padded beyond the requested transfer length.
+        ep.limit_transfer_len(transfer_len);
 
         debug!(
             "ALLOC EP{=usize} {} {}",
@@ -364,6 +1522,121 @@ impl Driver {
         );
     }
 
+    /// Deallocate a non-zero endpoint, and free its buffer
+    ///
+    /// Cancels the endpoint's transfers, and disables the endpoint. Returns
+    /// `InvalidEndpoint` if the endpoint is a control endpoint, or if it isn't
+    /// allocated. Returns `InvalidState` if the endpoint lent a transfer.
+    pub fn free_ep(&mut self, addr: EndpointAddress) -> Result<(), UsbError> {
+        if addr.index() == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let ep = self
+            .ep_allocator
+            .endpoint_mut(addr)
+            .ok_or(UsbError::InvalidEndpoint)?;
+        if ep.is_lent() {
+            return Err(UsbError::InvalidState);
+        }
+        let bit = ep.register_bit();
+        ep.flush(&self.usb);
+        ep.initialize(&self.usb);
+        ep.clear_complete(&self.usb);
+
+        let buffer = self.ep_allocator.deallocate_endpoint(addr).unwrap();
+        if !self.buffers.free(buffer) {
+            warn!("EP{=usize} memory lost", addr.index());
+        }
+        if addr.direction() == UsbDirection::Out {
+            self.ep_out &= !(1 << addr.index());
+            self.ep_out_pending &= !(1 << addr.index());
+        }
+        if self.nak_enabled & bit != 0 {
+            self.nak_enabled &= !bit;
+            self.nak &= !bit;
+            self.write_nak_enabled();
+        }
+        debug!("FREE EP{=usize} {}", addr.index(), addr.direction());
+        Ok(())
+    }
+
+    /// Reallocate a non-zero endpoint with a new type and max packet length
+    ///
+    /// Frees the endpoint if it's allocated, then allocates it again. If the
+    /// endpoint was enabled, the new endpoint is enabled, too.
+    pub fn realloc_ep(
+        &mut self,
+        addr: EndpointAddress,
+        kind: EndpointType,
+        max_packet_len: usize,
+    ) -> Result<(), UsbError> {
+        let enabled = self
+            .ep_allocator
+            .endpoint(addr)
+            .is_some_and(|ep| ep.is_enabled(&self.usb));
+        if self.is_allocated(addr) {
+            self.free_ep(addr)?;
+        } else if addr.index() == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.alloc_ep(addr.direction(), Some(addr), kind, max_packet_len)?;
+        if enabled {
+            self.enable_ep(addr);
+        }
+        Ok(())
+    }
+
+    /// Enable a single non-zero endpoint, priming it if it's an OUT endpoint
+    ///
+    /// Does nothing if the endpoint is a control endpoint, or if it isn't allocated.
+    pub fn enable_ep(&mut self, addr: EndpointAddress) {
+        if addr.index() == 0 {
+            return;
+        }
+        if let Some(ep) = self.ep_allocator.endpoint_mut(addr) {
+            ep.enable(&self.usb);
+            if addr.direction() == UsbDirection::Out {
+                ep.schedule_receives(&self.usb);
+            }
+        }
+    }
+
+    /// Disable a single non-zero endpoint, cancelling its transfers
+    ///
+    /// Does nothing if the endpoint is a control endpoint, or if it isn't allocated.
+    #[cfg(feature = "embassy")]
+    pub fn disable_ep(&mut self, addr: EndpointAddress) {
+        if addr.index() == 0 {
+            return;
+        }
+        if let Some(ep) = self.ep_allocator.endpoint_mut(addr) {
+            ep.flush(&self.usb);
+            ep.initialize(&self.usb);
+            if addr.direction() == UsbDirection::Out {
+                self.ep_out_pending &= !(1 << addr.index());
+            }
+        }
+    }
+
+    /// Returns the number of bytes received in the oldest, completed transfer
+    ///
+    /// Returns `None` if the endpoint isn't allocated, or if it has no completed
+    /// transfer.
+    #[cfg(feature = "embassy")]
+    pub fn ep_completed_len(&self, addr: EndpointAddress) -> Option<usize> {
+        self.ep_allocator.endpoint(addr)?.completed_len()
+    }
+
+    /// Indicates if the endpoint is enabled
+    ///
+    /// Returns `false` if the endpoint isn't allocated.
+    #[cfg(feature = "embassy")]
+    pub fn is_ep_enabled(&self, addr: EndpointAddress) -> bool {
+        self.ep_allocator
+            .endpoint(addr)
+            .is_some_and(|ep| ep.is_enabled(&self.usb))
+    }
+
     /// Invoked when the device transitions into the configured state
     pub fn on_configured(&mut self) {
         self.enable_endpoints();
@@ -380,32 +1653,113 @@ impl Driver {
     }
 
     /// Prime all non-zero, enabled OUT endpoints
+    ///
+    /// Schedules a receive on each of the endpoint's free TDs.
     fn prime_endpoints(&mut self) {
         for ep in self.ep_allocator.nonzero_endpoints_iter_mut() {
             if ep.is_enabled(&self.usb) && ep.address().direction() == UsbDirection::Out {
-                let max_packet_len = ep.max_packet_len();
-                ep.schedule_transfer(&self.usb, max_packet_len);
+                ep.schedule_receives(&self.usb);
             }
         }
     }
 
-    /// Initialize (or reinitialize) all non-zero endpoints
-    fn initialize_endpoints(&mut self) {
+    /// Confirm the primes of all endpoints, priming again if a prime failed
+    fn confirm_primes(&mut self) {
+        for ep in self.ep_allocator.endpoints_iter_mut() {
+            ep.confirm_prime(&self.usb);
+        }
+    }
+
+    /// Prime the isochronous endpoints that wait for a (micro)frame boundary
+    ///
+    /// Once no prime waits, this disables SOF interrupts, unless the user
+    /// enabled them.
+    fn prime_deferred(&mut self) {
+        let mut deferred = false;
         for ep in self.ep_allocator.nonzero_endpoints_iter_mut() {
+            deferred |= ep.prime_deferred(&self.usb);
+        }
+        if !deferred && !self.sof_interrupts {
+            ral::modify_reg!(ral::usb, self.usb, USBINTR, SRE: 0);
+        }
+    }
+
+    /// Initialize (or reinitialize) all endpoints
+    ///
+    /// Control endpoints only forget their scheduled transfers.
+    fn initialize_endpoints(&mut self) {
+        for ep in self.ep_allocator.endpoints_iter_mut() {
             ep.initialize(&self.usb);
         }
+        self.ep_out_pending = 0;
+    }
+
+    /// Simulate OUT endpoints that hold unread transfers
+    ///
+    /// The simulated USBSTS can't clear single bits, so clearing a timer's flag
+    /// also clears UI. Pending OUT endpoints still produce data events.
+    #[cfg(all(test, feature = "embassy"))]
+    pub fn simulate_ep_out_pending(&mut self, ep_out: u16) {
+        self.ep_out_pending = ep_out;
     }
 
     /// Poll for reset or USB traffic
+    ///
+    /// Reports a VBUS detach as a suspend. Reports a NAK, or a SOF when SOF
+    /// interrupts are enabled, without any other traffic as data without any
+    /// endpoints.
     pub fn poll(&mut self) -> PollResult {
+        if let Some(VbusEvent::Detached) = self.poll_vbus() {
+            return PollResult::Suspend;
+        }
+        self.poll_force_reset();
+        self.confirm_primes();
+        self.prime_deferred();
+
         let usbsts = ral::read_reg!(ral::usb, self.usb, USBSTS);
         use ral::usb::USBSTS;
 
+        let sof = usbsts & USBSTS::SRI::mask != 0;
+        if sof {
+            ral::write_reg!(ral::usb, self.usb, USBSTS, SRI: 1);
+            self.sof = true;
+        }
+
         if usbsts & USBSTS::URI::mask != 0 {
             ral::write_reg!(ral::usb, self.usb, USBSTS, URI: 1);
             return PollResult::Reset;
         }
 
+        // NAKI clears once the enabled ENDPTNAK bits are clear.
+        let mut nak = 0;
+        if usbsts & USBSTS::NAKI::mask != 0 {
+            nak = ral::read_reg!(ral::usb, self.usb, ENDPTNAK) & self.nak_enabled;
+            ral::write_reg!(ral::usb, self.usb, ENDPTNAK, nak);
+            self.nak |= nak;
+        }
+
+        if let Some(timer) = self.remote_wakeup
+            && self.gpt_mut(timer, |gpt| gpt.is_elapsed())
+        {
+            self.end_remote_wakeup();
+            return PollResult::Resume;
+        }
+
+        if usbsts & USBSTS::SLI::mask != 0 {
+            ral::write_reg!(ral::usb, self.usb, USBSTS, SLI: 1);
+            if !self.suspended && ral::read_reg!(ral::usb, self.usb, PORTSC1, SUSP == 1) {
+                return PollResult::Suspend;
+            }
+        }
+
+        if usbsts & USBSTS::PCI::mask != 0 {
+            ral::write_reg!(ral::usb, self.usb, USBSTS, PCI: 1);
+            self.update_speed();
+            if self.suspended && ral::read_reg!(ral::usb, self.usb, PORTSC1, SUSP == 0) {
+                return PollResult::Resume;
+            }
+        }
+
         if usbsts & USBSTS::UI::mask != 0 {
             ral::write_reg!(ral::usb, self.usb, USBSTS, UI: 1);
 
@@ -416,7 +1770,8 @@ impl Driver {
             );
             // Note: could be complete in one register read, but this is a little
             // easier to comprehend...
-            self.ep_out = ral::read_reg!(ral::usb, self.usb, ENDPTCOMPLETE, ERCE) as u16;
+            self.ep_out = ral::read_reg!(ral::usb, self.usb, ENDPTCOMPLETE, ERCE) as u16
+                | self.ep_out_pending;
 
             let ep_in_complete = ral::read_reg!(ral::usb, self.usb, ENDPTCOMPLETE, ETCE);
             ral::write_reg!(ral::usb, self.usb, ENDPTCOMPLETE, ETCE: ep_in_complete);
@@ -428,8 +1783,670 @@ impl Driver {
                 ep_in_complete: ep_in_complete as u16,
                 ep_setup,
             }
+        } else if self.ep_out_pending != 0 {
+            self.ep_out |= self.ep_out_pending;
+            PollResult::Data {
+                ep_out: self.ep_out_pending,
+                ep_in_complete: 0,
+                ep_setup: 0,
+            }
+        } else if nak != 0 || (sof && self.sof_interrupts) {
+            PollResult::Data {
+                ep_out: 0,
+                ep_in_complete: 0,
+                ep_setup: 0,
+            }
         } else {
             PollResult::None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::Driver;
+    use crate::{
+        CachePolicy, Placement,
+        buffer::{self, EndpointMemory},
+        ral,
+        state::EndpointState,
+    };
+    use std::boxed::Box;
+    use usb_device::{
+        UsbDirection, UsbError,
+        bus::PollResult,
+        endpoint::{EndpointAddress, EndpointType},
+    };
+
+    /// Create a driver that uses simulated registers.
+    ///
+    /// Skips the controller initialization, which waits on the controller.
+    fn driver<const SIZE: usize>() -> Driver {
+        let registers = ral::sim::Registers::leak();
+        let memory: &'static EndpointMemory<SIZE> = Box::leak(Box::new(EndpointMemory::new()));
+        let state: &'static EndpointState<8, 2> = Box::leak(Box::new(EndpointState::new()));
+        Driver::new(registers.instances(), memory, state)
+    }
+
+    #[test]
+    fn memory_usage() {
+        let mut usb = driver::<1024>();
+        let usage = usb.memory_usage();
+        assert_eq!(usage.total, 1024);
+        assert_eq!(usage.used, 0);
+        assert_eq!(usage.free, 1024);
+        assert_eq!(usage.endpoint_capacity, 8);
+        assert_eq!(usage.endpoints_allocated, 0);
+        assert_eq!(usage.endpoints_required, 0);
+
+        let ctrl_out = EndpointAddress::from_parts(0, UsbDirection::Out);
+        usb.alloc_ep(UsbDirection::Out, Some(ctrl_out), EndpointType::Control, 64)
+            .unwrap();
+        let bulk_in = usb
+            .alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 40)
+            .unwrap();
+
+        let usage = usb.memory_usage();
+        // 64 for control, and two 40 byte transfers padded to 96.
+        assert_eq!(usage.used, 64 + 96);
+        assert_eq!(usage.free, 1024 - 64 - 96);
+        assert_eq!(usage.high_water_mark, 64 + 96);
+        assert_eq!(usage.endpoints_allocated, 2);
+        assert_eq!(usage.endpoints_required, 4);
+
+        let table = usb.endpoint_usage();
+        let ctrl = table[0].unwrap();
+        assert_eq!(ctrl.address, ctrl_out);
+        assert_eq!(ctrl.kind, EndpointType::Control);
+        assert_eq!(ctrl.buffer_len, 64);
+        assert_eq!(ctrl.reserved, 64);
+        assert_eq!(ctrl.buffer_address % 32, 0);
+        let bulk = table[3].unwrap();
+        assert_eq!(bulk.address, bulk_in);
+        assert_eq!(bulk.buffer_len, 80);
+        assert_eq!(bulk.reserved, 96);
+        assert_eq!(bulk.state_index, 3);
+        assert_eq!(table.iter().flatten().count(), 2);
+
+        // Out of memory.
+        assert_eq!(
+            usb.alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 512),
+            Err(UsbError::EndpointMemoryOverflow)
+        );
+        assert_eq!(usb.memory_usage().used, 64 + 96);
+    }
+
+    #[test]
+    fn padded_transfer_len() {
+        let mut usb = driver::<{ 64 * 1024 }>();
+        let addr = EndpointAddress::from_parts(1, UsbDirection::Out);
+        usb.set_max_transfer_len(addr, 17000).unwrap();
+        usb.alloc_ep(UsbDirection::Out, Some(addr), EndpointType::Bulk, 512)
+            .unwrap();
+
+        // Each transfer starts on a page, but transfers stay a multiple of the
+        // max packet length.
+        assert_eq!(usb.max_transfer_len(addr), Some(16896));
+        let usage = usb.endpoint_usage()[crate::state::index(addr)].unwrap();
+        assert_eq!(usage.buffer_len, 2 * 20480);
+        assert_eq!(usage.buffer_address % 4096, 0);
+    }
+
+    #[test]
+    fn endpoint_regions() {
+        #[repr(align(32))]
+        struct Aligned([u8; 1024]);
+
+        let mut usb = driver::<256>();
+        let region: &'static mut [u8] = &mut Box::leak(Box::new(Aligned([0; 1024]))).0;
+        assert_eq!(
+            usb.add_endpoint_region(
+                buffer::Allocator::new(region, CachePolicy::Skip),
+                Placement::Kind(EndpointType::Bulk),
+            ),
+            Some(1)
+        );
+        assert_eq!(usb.memory_usage().total, 256 + 1024);
+
+        let ctrl_out = EndpointAddress::from_parts(0, UsbDirection::Out);
+        usb.alloc_ep(UsbDirection::Out, Some(ctrl_out), EndpointType::Control, 64)
+            .unwrap();
+        let bulk_in = usb
+            .alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 512)
+            .unwrap();
+        let table = usb.endpoint_usage();
+        assert_eq!(table[0].unwrap().region, 0);
+        assert_eq!(table[crate::state::index(bulk_in)].unwrap().region, 1);
+        assert_eq!(usb.memory_usage().used, 64 + 1024);
+
+        while usb.has_endpoint_region_room() {
+            let region: &'static mut [u8] = Box::leak(Box::new([0; 32]));
+            usb.add_endpoint_region(
+                buffer::Allocator::new(region, CachePolicy::Skip),
+                Placement::Any,
+            )
+            .unwrap();
+        }
+        let region: &'static mut [u8] = Box::leak(Box::new([0; 32]));
+        assert!(
+            usb.add_endpoint_region(
+                buffer::Allocator::new(region, CachePolicy::Skip),
+                Placement::Any
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn nak_events() {
+        let mut usb = driver::<1024>();
+        let bulk_in = usb
+            .alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64)
+            .unwrap();
+        let bulk_out = usb
+            .alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64)
+            .unwrap();
+        let unallocated = EndpointAddress::from_parts(5, UsbDirection::In);
+        assert_eq!(
+            usb.set_nak_interrupts(unallocated, true),
+            Err(UsbError::InvalidEndpoint)
+        );
+
+        usb.set_nak_interrupts(bulk_in, true).unwrap();
+        let in_bit = 1 << (16 + bulk_in.index());
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTNAKEN), in_bit);
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, USBINTR, NAKE), 1);
+
+        // Only endpoints with NAK interrupts are reported.
+        let out_bit = 1 << bulk_out.index();
+        ral::write_reg!(ral::usb, usb.usb, ENDPTNAK, in_bit | out_bit);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, NAKI: 1);
+        assert!(matches!(
+            usb.poll(),
+            PollResult::Data {
+                ep_out: 0,
+                ep_in_complete: 0,
+                ep_setup: 0
+            }
+        ));
+        let events = usb.take_nak_events();
+        assert!(events.contains(bulk_in));
+        assert!(!events.contains(bulk_out));
+        assert_eq!(events.ep_in, 1 << bulk_in.index());
+        assert!(usb.take_nak_events().is_empty());
+
+        // A NAK on an endpoint without NAK interrupts isn't an event.
+        ral::write_reg!(ral::usb, usb.usb, ENDPTNAK, out_bit);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, NAKI: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert!(usb.take_nak_events().is_empty());
+
+        usb.set_nak_interrupts(bulk_in, false).unwrap();
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTNAKEN), 0);
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, USBINTR, NAKE), 0);
+
+        // A bus reset disables NAK interrupts, and drops NAK events.
+        usb.set_nak_interrupts(bulk_in, true).unwrap();
+        usb.set_nak_interrupts(bulk_out, true).unwrap();
+        ral::write_reg!(ral::usb, usb.usb, ENDPTNAK, in_bit);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, NAKI: 1);
+        usb.poll();
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, PR: 1);
+        usb.bus_reset();
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTNAKEN), 0);
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, USBINTR, NAKE), 0);
+        assert!(usb.take_nak_events().is_empty());
+
+        // Freeing an endpoint disables its NAK interrupts.
+        usb.set_nak_interrupts(bulk_in, true).unwrap();
+        usb.set_nak_interrupts(bulk_out, true).unwrap();
+        usb.free_ep(bulk_out).unwrap();
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTNAKEN), in_bit);
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, USBINTR, NAKE), 1);
+    }
+
+    #[test]
+    fn suspend_resume() {
+        let mut usb = driver::<64>();
+
+        // Idle, but not suspended.
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, SLI: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, SLI: 1);
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, SUSP: 1);
+        assert!(matches!(usb.poll(), PollResult::Suspend));
+        usb.suspend();
+        assert!(ral::read_reg!(ral::usb, usb.usb, PORTSC1, PHCD == 1));
+        assert_ne!(ral::read_reg!(ral::usbphy, usb.phy, PWD_SET), 0);
+
+        // Another idle indication doesn't suspend again.
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, SLI: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, PCI: 1);
+        ral::modify_reg!(ral::usb, usb.usb, PORTSC1, SUSP: 0);
+        assert!(matches!(usb.poll(), PollResult::Resume));
+        usb.resume();
+        assert!(ral::read_reg!(ral::usb, usb.usb, PORTSC1, PHCD == 0));
+        assert_eq!(
+            ral::read_reg!(ral::usbphy, usb.phy, PWD_CLR),
+            ral::read_reg!(ral::usbphy, usb.phy, PWD_SET)
+        );
+    }
+
+    #[test]
+    fn remote_wakeup() {
+        use crate::gpt::Instance;
+
+        let mut usb = driver::<64>();
+
+        // Not suspended.
+        assert_eq!(
+            usb.remote_wakeup(Instance::Gpt0),
+            Err(UsbError::InvalidState)
+        );
+
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, SLI: 1);
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, SUSP: 1);
+        assert!(matches!(usb.poll(), PollResult::Suspend));
+        usb.suspend();
+
+        // The timer is in use.
+        usb.set_force_reset_timer(Instance::Gpt1, 10_000);
+        assert_eq!(
+            usb.remote_wakeup(Instance::Gpt1),
+            Err(UsbError::InvalidState)
+        );
+
+        usb.remote_wakeup(Instance::Gpt0).unwrap();
+        assert!(ral::read_reg!(ral::usb, usb.usb, PORTSC1, FPR == 1));
+        assert!(ral::read_reg!(ral::usb, usb.usb, PORTSC1, PHCD == 0));
+        assert!(ral::read_reg!(ral::usb, usb.usb, GPTIMER0CTRL, GPTRUN == 1));
+        // Already signaling.
+        assert_eq!(
+            usb.remote_wakeup(Instance::Gpt0),
+            Err(UsbError::InvalidState)
+        );
+
+        // Signaling continues until the timer elapses.
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, 0);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert!(ral::read_reg!(ral::usb, usb.usb, PORTSC1, FPR == 1));
+
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, TI0: 1);
+        assert!(matches!(usb.poll(), PollResult::Resume));
+        assert!(ral::read_reg!(ral::usb, usb.usb, PORTSC1, FPR == 0));
+        assert!(ral::read_reg!(ral::usb, usb.usb, GPTIMER0CTRL, GPTRUN == 0));
+    }
+
+    #[test]
+    fn detach_attach() {
+        let mut usb = driver::<64>();
+        usb.attach();
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 1));
+
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, PSPD: 2);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, PCI: 1);
+        usb.poll();
+        assert!(usb.negotiated_speed().is_some());
+
+        usb.detach();
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 0));
+        assert_eq!(usb.negotiated_speed(), None);
+
+        usb.attach();
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 1));
+    }
+
+    #[test]
+    fn force_reset() {
+        use crate::gpt::Instance;
+
+        let mut usb = driver::<64>();
+        usb.attach();
+        assert_eq!(usb.force_reset(), Err(UsbError::Unsupported));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 1));
+
+        usb.set_force_reset_timer(Instance::Gpt1, 5_000);
+        usb.force_reset().unwrap();
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 0));
+        assert!(ral::read_reg!(ral::usb, usb.usb, GPTIMER1CTRL, GPTRUN == 1));
+
+        // Stays detached until the timer elapses. (The simulated USBSTS
+        // can't clear single bits.)
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, 0);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 0));
+
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, TI1: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 1));
+
+        // The host resets the device once it attaches.
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, URI: 1);
+        assert!(matches!(usb.poll(), PollResult::Reset));
+    }
+
+    #[test]
+    fn vbus_detection() {
+        use super::VbusEvent;
+        use crate::gpt::Instance;
+
+        let mut usb = driver::<1024>();
+        let bulk_out = usb
+            .alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64)
+            .unwrap();
+
+        // No VBUS. Enabling only clears BSVIS, and keeps the other pending
+        // OTGSC status. (The simulated OTGSC holds the last written value.)
+        ral::write_reg!(ral::usb, usb.usb, OTGSC, IDIS: 1, BSVIS: 1, DPIS: 1, DPIE: 1);
+        usb.enable_vbus_detection(Instance::Gpt1);
+        usb.attach();
+        assert_eq!(usb.is_vbus_present(), Some(false));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 0));
+        assert_eq!(
+            ral::read_reg!(ral::usb, usb.usb, OTGSC),
+            ral::usb::OTGSC::BSVIS::mask
+                | ral::usb::OTGSC::BSVIE::mask
+                | ral::usb::OTGSC::DPIE::mask
+        );
+
+        // VBUS changes, but hasn't debounced.
+        ral::write_reg!(ral::usb, usb.usb, OTGSC, BSV: 1, BSVIS: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert_eq!(usb.take_vbus_event(), None);
+        assert!(ral::read_reg!(ral::usb, usb.usb, GPTIMER1CTRL, GPTRUN == 1));
+
+        ral::write_reg!(ral::usb, usb.usb, OTGSC, BSV: 1);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, TI1: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert_eq!(usb.take_vbus_event(), Some(VbusEvent::Attached));
+        assert_eq!(usb.is_vbus_present(), Some(true));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 1));
+
+        // Debounced, but no change.
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert_eq!(usb.take_vbus_event(), None);
+
+        // Losing VBUS detaches, like detach(), and reports a suspend.
+        usb.enable_ep(bulk_out);
+        ral::write_reg!(ral::usb, usb.usb, OTGSC, BSV: 0);
+        assert!(matches!(usb.poll(), PollResult::Suspend));
+        assert_eq!(usb.take_vbus_event(), Some(VbusEvent::Detached));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 0));
+        assert!(
+            !usb.ep_allocator
+                .endpoint(bulk_out)
+                .unwrap()
+                .is_enabled(&usb.usb)
+        );
+
+        // The user still wants to attach, so VBUS attaches again.
+        ral::write_reg!(ral::usb, usb.usb, OTGSC, BSV: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert_eq!(usb.take_vbus_event(), Some(VbusEvent::Attached));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 1));
+    }
+
+    #[test]
+    fn vbus_detection_analog_status() {
+        use super::VbusEvent;
+        use crate::gpt::Instance;
+
+        const VBUS_VALID: u32 = 1 << 3;
+        let (status, status_ro) = ral::sim::ro_register();
+
+        // The B session is valid, but the analog detector doesn't see VBUS.
+        let mut usb = driver::<64>();
+        usb.set_vbus_detect_status(status_ro);
+        ral::write_reg!(ral::usb, usb.usb, OTGSC, BSV: 1);
+        usb.enable_vbus_detection(Instance::Gpt1);
+        usb.attach();
+        assert_eq!(usb.is_vbus_present(), Some(false));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 0));
+
+        status.write(VBUS_VALID);
+        ral::write_reg!(ral::usb, usb.usb, OTGSC, BSV: 1);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, TI1: 1);
+        usb.poll();
+        assert_eq!(usb.take_vbus_event(), Some(VbusEvent::Attached));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 1));
+
+        // The simulated timer stays elapsed, so this debounces right away.
+        status.write(0);
+        usb.poll();
+        assert_eq!(usb.take_vbus_event(), Some(VbusEvent::Detached));
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBCMD, RS == 0));
+    }
+
+    #[test]
+    fn timer_in_use() {
+        use crate::gpt::Instance;
+        use std::panic::{self, AssertUnwindSafe};
+
+        let force_reset: fn(&mut Driver, Instance) =
+            |usb, timer| usb.set_force_reset_timer(timer, 5_000);
+        let vbus: fn(&mut Driver, Instance) = |usb, timer| usb.enable_vbus_detection(timer);
+
+        // VBUS detection and forced resets can't share a timer.
+        for (first, second) in [(force_reset, vbus), (vbus, force_reset)] {
+            for (timer, panics) in [(Instance::Gpt0, true), (Instance::Gpt1, false)] {
+                let mut usb = driver::<64>();
+                first(&mut usb, Instance::Gpt0);
+                let result = panic::catch_unwind(AssertUnwindSafe(|| second(&mut usb, timer)));
+                assert_eq!(result.is_err(), panics);
+            }
+        }
+    }
+
+    #[test]
+    fn negotiated_speed() {
+        use super::BusSpeed;
+
+        let mut usb = driver::<64>();
+        assert_eq!(usb.negotiated_speed(), None);
+
+        // Reset in progress.
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, PR: 1, PSPD: 2);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, PCI: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert_eq!(usb.negotiated_speed(), None);
+
+        for (pspd, speed) in [(2, BusSpeed::High), (0, BusSpeed::Full), (1, BusSpeed::Low)] {
+            ral::write_reg!(ral::usb, usb.usb, PORTSC1, PSPD: pspd);
+            ral::write_reg!(ral::usb, usb.usb, USBSTS, PCI: 1);
+            assert!(matches!(usb.poll(), PollResult::None));
+            assert_eq!(usb.negotiated_speed(), Some(speed));
+        }
+
+        // The next reset forgets the speed.
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, PR: 1);
+        usb.bus_reset();
+        assert_eq!(usb.negotiated_speed(), None);
+    }
+
+    #[test]
+    fn sof() {
+        let mut usb = driver::<64>();
+        assert!(!usb.take_sof());
+
+        usb.set_sof_interrupts(true);
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBINTR, SRE == 1));
+
+        // (FRINDEX, frame, microframe). The frame number is 11 bits.
+        for (frindex, frame, microframe) in [(0x5A5 << 3 | 3, 0x5A5, 3), (0x3FFF, 0x7FF, 7)] {
+            ral::write_reg!(ral::usb, usb.usb, FRINDEX, frindex);
+            assert_eq!(usb.frame_number(), frame);
+            assert_eq!(usb.microframe(), microframe);
+        }
+
+        // A SOF alone reports data without any endpoints.
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, SRI: 1);
+        assert!(matches!(
+            usb.poll(),
+            PollResult::Data {
+                ep_out: 0,
+                ep_in_complete: 0,
+                ep_setup: 0
+            }
+        ));
+        assert!(usb.take_sof());
+        assert!(!usb.take_sof());
+
+        // A SOF with endpoint traffic reports the traffic.
+        ral::write_reg!(ral::usb, usb.usb, ENDPTSETUPSTAT, 1);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, SRI: 1, UI: 1);
+        assert!(matches!(
+            usb.poll(),
+            PollResult::Data {
+                ep_out: 0,
+                ep_in_complete: 0,
+                ep_setup: 1
+            }
+        ));
+        assert!(usb.take_sof());
+
+        ral::write_reg!(ral::usb, usb.usb, ENDPTSETUPSTAT, 0);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, 0);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert!(!usb.take_sof());
+
+        usb.set_sof_interrupts(false);
+        assert!(ral::read_reg!(ral::usb, usb.usb, USBINTR, SRE == 0));
+    }
+
+    #[test]
+    fn isochronous_start_frame() {
+        use super::Frame;
+        use usb_device::endpoint::{IsochronousSynchronizationType, IsochronousUsageType};
+
+        let mut usb = driver::<1024>();
+        let iso_in = usb
+            .alloc_ep(
+                UsbDirection::In,
+                None,
+                EndpointType::Isochronous {
+                    synchronization: IsochronousSynchronizationType::Asynchronous,
+                    usage: IsochronousUsageType::Data,
+                },
+                64,
+            )
+            .unwrap();
+        usb.enable_ep(iso_in);
+        let bit = 1 << (16 + iso_in.index());
+
+        // High speed.
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, PSPD: 2);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, PCI: 1);
+        usb.poll();
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, 0);
+
+        // Frame 0x20, microframe 0. The prime waits for the next microframe.
+        ral::write_reg!(ral::usb, usb.usb, FRINDEX, 0x100);
+        assert_eq!(usb.ep_write(&[0; 8], iso_in), Ok(8));
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTPRIME) & bit, 0);
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, USBINTR, SRE), 1);
+        assert_eq!(usb.isochronous_start_frame(iso_in), None);
+
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTPRIME) & bit, 0);
+
+        // The SOF of microframe 1 primes the endpoint, and the first transfer
+        // moves in microframe 2. The user didn't enable SOF interrupts, so poll()
+        // doesn't report the SOF, and SOF interrupts turn off.
+        ral::write_reg!(ral::usb, usb.usb, FRINDEX, 0x101);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, SRI: 1);
+        assert!(matches!(usb.poll(), PollResult::None));
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTPRIME) & bit, bit);
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, USBINTR, SRE), 0);
+        assert_eq!(
+            usb.isochronous_start_frame(iso_in),
+            Some(Frame {
+                number: 0x20,
+                microframe: 2
+            })
+        );
+
+        // At full speed, the first transfer moves in the next frame.
+        ral::write_reg!(ral::usb, usb.usb, PORTSC1, PSPD: 0);
+        ral::write_reg!(ral::usb, usb.usb, USBSTS, PCI: 1);
+        usb.poll();
+        assert_eq!(
+            usb.isochronous_start_frame(iso_in),
+            Some(Frame {
+                number: 0x21,
+                microframe: 0
+            })
+        );
+    }
+
+    #[test]
+    fn teardown_flushes_endpoints() {
+        let detach: fn(&mut Driver) = |usb| usb.detach();
+        let bus_reset: fn(&mut Driver) = |usb| {
+            ral::write_reg!(ral::usb, usb.usb, PORTSC1, PR: 1);
+            usb.bus_reset();
+        };
+
+        // (teardown, prime still in progress). The simulated controller
+        // finishes a prime that's in progress after the first flush.
+        for (teardown, late_prime) in [
+            (detach, false),
+            (detach, true),
+            (bus_reset, false),
+            (bus_reset, true),
+        ] {
+            let mut usb = driver::<1024>();
+            let bulk_out = usb
+                .alloc_ep(UsbDirection::Out, None, EndpointType::Bulk, 64)
+                .unwrap();
+            usb.enable_ep(bulk_out);
+            let bit = 1 << bulk_out.index();
+            assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTPRIME), bit);
+            if !late_prime {
+                // The controller already primed the endpoint.
+                ral::write_reg!(ral::usb, usb.usb, ENDPTPRIME, 0);
+                ral::write_reg!(ral::usb, usb.usb, ENDPTSTAT, bit);
+            }
+            assert!(usb.ep_status(bulk_out).unwrap().primed);
+
+            teardown(&mut usb);
+            assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTPRIME), 0);
+            assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTSTAT), 0);
+            assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTFLUSH), 0);
+            assert!(!usb.ep_status(bulk_out).unwrap().primed);
+            assert!(
+                !usb.ep_allocator
+                    .endpoint(bulk_out)
+                    .unwrap()
+                    .is_enabled(&usb.usb)
+            );
+        }
+    }
+
+    #[test]
+    fn commit_lent_buffer() {
+        let mut usb = driver::<1024>();
+        let bulk_in = usb
+            .alloc_ep(UsbDirection::In, None, EndpointType::Bulk, 64)
+            .unwrap();
+        let bit = 1 << (16 + bulk_in.index());
+        assert_eq!(usb.ep_commit(bulk_in, 8), Err(UsbError::InvalidState));
+
+        // Disabled, then stalled. The endpoint keeps the lent buffer.
+        usb.ep_lend(bulk_in).unwrap();
+        assert_eq!(usb.ep_commit(bulk_in, 8), Err(UsbError::InvalidState));
+        usb.enable_ep(bulk_in);
+        usb.ep_stall(true, bulk_in);
+        assert_eq!(usb.ep_commit(bulk_in, 8), Err(UsbError::InvalidState));
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTPRIME), 0);
+
+        usb.ep_stall(false, bulk_in);
+        assert_eq!(usb.ep_commit(bulk_in, 8), Ok(8));
+        assert_eq!(ral::read_reg!(ral::usb, usb.usb, ENDPTPRIME), bit);
+        assert_eq!(usb.ep_commit(bulk_in, 8), Err(UsbError::InvalidState));
+    }
+}
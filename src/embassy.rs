@@ -0,0 +1,1195 @@
+//! An `embassy-usb-driver` implementation
+//!
+//! Enable the `embassy` feature to use this module. It provides a
+//! [`Driver`] that you can hand to `embassy-usb`, as an alternative to the
+//! `usb-device` [`BusAdapter`](crate::BusAdapter).
+//!
+//! The driver shares its state with your USB interrupt handler through a
+//! static [`DriverState`]. Enable the USB interrupt, and call
+//! [`DriverState::on_interrupt()`] from the handler. The interrupt handler
+//! services the controller, then wakes the tasks that are waiting on the bus,
+//! the control pipe, or a specific endpoint. It also puts the PHY into
+//! low-power mode when the bus suspends, and restores the PHY when the bus
+//! resumes.
+//!
+//! The driver uses `critical-section` to synchronize with the interrupt
+//! handler. Your program needs a `critical-section` implementation, like
+//! the one provided by `cortex-m`'s `critical-section-single-core` feature.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use imxrt_ral as ral;
+//! use imxrt_usbd::{Instances, Speed, embassy::{Driver, DriverState}};
+//!
+//! static EP_MEMORY: imxrt_usbd::EndpointMemory<2048> = imxrt_usbd::EndpointMemory::new();
+//! static EP_STATE: imxrt_usbd::EndpointState = imxrt_usbd::EndpointState::max_endpoints();
+//! static DRIVER_STATE: DriverState = DriverState::new();
+//!
+//! let instances = Instances {
+//!     usb: unsafe { ral::usb::USB::instance() },
+//!     usbnc: unsafe { ral::usbnc::USBNC::instance() },
+//!     usbphy: unsafe { ral::usbphy::USBPHY::instance() },
+//! };
+//!
+//! let driver = Driver::new(instances, &EP_MEMORY, &EP_STATE, &DRIVER_STATE, Speed::High);
+//! // Hand the driver to embassy-usb...
+//!
+//! // In your USB interrupt handler:
+//! DRIVER_STATE.on_interrupt();
+//! ```
+
+use crate::{driver, state};
+use core::{
+    cell::RefCell,
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+use critical_section::Mutex;
+use embassy_usb_driver::{
+    Direction, EndpointAddress, EndpointAllocError, EndpointError, EndpointInfo, EndpointType,
+    Event, Unsupported,
+};
+use usb_device::{UsbDirection, UsbError, bus::PollResult};
+
+/// Convert an embassy endpoint address into a usb-device endpoint address
+fn usb_device_address(addr: EndpointAddress) -> usb_device::endpoint::EndpointAddress {
+    u8::from(addr).into()
+}
+
+/// Convert a usb-device endpoint address into an embassy endpoint address
+fn embassy_address(addr: usb_device::endpoint::EndpointAddress) -> EndpointAddress {
+    u8::from(addr).into()
+}
+
+/// Convert an embassy endpoint type into a usb-device endpoint type
+fn usb_device_type(ep_type: EndpointType) -> usb_device::endpoint::EndpointType {
+    use usb_device::endpoint::{IsochronousSynchronizationType, IsochronousUsageType};
+    match ep_type {
+        EndpointType::Control => usb_device::endpoint::EndpointType::Control,
+        EndpointType::Isochronous => usb_device::endpoint::EndpointType::Isochronous {
+            synchronization: IsochronousSynchronizationType::NoSynchronization,
+            usage: IsochronousUsageType::Data,
+        },
+        EndpointType::Bulk => usb_device::endpoint::EndpointType::Bulk,
+        EndpointType::Interrupt => usb_device::endpoint::EndpointType::Interrupt,
+    }
+}
+
+/// State shared between the driver and the interrupt handler
+struct Inner {
+    driver: Option<driver::Driver>,
+    /// Set by the interrupt handler, consumed by `Bus::poll()`.
+    reset: bool,
+    suspend: bool,
+    resume: bool,
+    /// `PowerDetected` or `PowerRemoved`.
+    power: Option<Event>,
+    /// Times remote wakeup signaling.
+    remote_wakeup_timer: Option<crate::gpt::Instance>,
+    bus_waker: Option<Waker>,
+    control_waker: Option<Waker>,
+    /// Indexed by [`state::index`].
+    ep_wakers: [Option<Waker>; state::MAX_ENDPOINTS],
+}
+
+impl Inner {
+    /// Acquire the USB driver
+    ///
+    /// # Panics
+    ///
+    /// Panics if the state isn't assigned to a [`Driver`].
+    fn driver(&mut self) -> &mut driver::Driver {
+        self.driver
+            .as_mut()
+            .expect("Driver state isn't assigned to a driver")
+    }
+
+    fn wake_bus(&mut self) {
+        if let Some(waker) = self.bus_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_control(&mut self) {
+        if let Some(waker) = self.control_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_endpoint(&mut self, addr: usb_device::endpoint::EndpointAddress) {
+        if let Some(waker) = self
+            .ep_wakers
+            .get_mut(state::index(addr))
+            .and_then(Option::take)
+        {
+            waker.wake();
+        }
+    }
+
+    fn wake_endpoints(&mut self) {
+        for waker in self.ep_wakers.iter_mut().filter_map(Option::take) {
+            waker.wake();
+        }
+    }
+
+    fn register_endpoint(&mut self, addr: usb_device::endpoint::EndpointAddress, waker: &Waker) {
+        if let Some(slot) = self.ep_wakers.get_mut(state::index(addr)) {
+            slot.replace(waker.clone());
+        }
+    }
+}
+
+/// Driver state, shared with the USB interrupt handler
+///
+/// Allocate this in a `static`, then supply it to [`Driver::new()`]. Call
+/// [`on_interrupt()`](DriverState::on_interrupt) from your USB interrupt
+/// handler.
+pub struct DriverState {
+    inner: Mutex<RefCell<Inner>>,
+}
+
+impl Default for DriverState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DriverState {
+    /// Allocate the driver state
+    pub const fn new() -> Self {
+        DriverState {
+            inner: Mutex::new(RefCell::new(Inner {
+                driver: None,
+                reset: false,
+                suspend: false,
+                resume: false,
+                power: None,
+                remote_wakeup_timer: None,
+                bus_waker: None,
+                control_waker: None,
+                ep_wakers: [const { None }; state::MAX_ENDPOINTS],
+            })),
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        critical_section::with(|cs| f(&mut self.inner.borrow_ref_mut(cs)))
+    }
+
+    /// Service the USB controller, and wake any waiting tasks
+    ///
+    /// Call this from your USB interrupt handler. This does nothing if the
+    /// state isn't assigned to a [`Driver`].
+    pub fn on_interrupt(&self) {
+        self.with(|inner| {
+            let Some(driver) = inner.driver.as_mut() else {
+                return;
+            };
+            let result = driver.poll();
+            let vbus_event = driver.take_vbus_event();
+            let detached = vbus_event == Some(crate::VbusEvent::Detached);
+            if detached {
+                driver.suspend();
+            }
+            match result {
+                PollResult::Reset => {
+                    driver.bus_reset();
+                    inner.reset = true;
+                    inner.suspend = false;
+                    inner.wake_bus();
+                    inner.wake_control();
+                    inner.wake_endpoints();
+                }
+                PollResult::Data {
+                    ep_out,
+                    ep_in_complete,
+                    ep_setup,
+                } => {
+                    if (ep_out | ep_in_complete | ep_setup) & 1 != 0 {
+                        inner.wake_control();
+                    }
+                    for index in 1..8 {
+                        let mask = 1 << index;
+                        if ep_out & mask != 0 {
+                            inner.wake_endpoint(usb_device::endpoint::EndpointAddress::from_parts(
+                                index,
+                                UsbDirection::Out,
+                            ));
+                        }
+                        if ep_in_complete & mask != 0 {
+                            inner.wake_endpoint(usb_device::endpoint::EndpointAddress::from_parts(
+                                index,
+                                UsbDirection::In,
+                            ));
+                        }
+                    }
+                }
+                // The driver reports a detach as a suspend.
+                PollResult::Suspend if detached => {}
+                PollResult::Suspend => {
+                    driver.suspend();
+                    inner.suspend = true;
+                    inner.resume = false;
+                    inner.wake_bus();
+                }
+                PollResult::Resume => {
+                    driver.resume();
+                    inner.resume = true;
+                    inner.suspend = false;
+                    inner.wake_bus();
+                }
+                PollResult::None => {}
+            }
+            // A power event doesn't hide the other events of the same poll.
+            match vbus_event {
+                Some(crate::VbusEvent::Attached) => {
+                    inner.power = Some(Event::PowerDetected);
+                    inner.wake_bus();
+                }
+                Some(crate::VbusEvent::Detached) => {
+                    inner.power = Some(Event::PowerRemoved);
+                    inner.suspend = false;
+                    inner.wake_bus();
+                }
+                None => {}
+            }
+        })
+    }
+}
+
+/// An `embassy-usb-driver` USB driver
+///
+/// Use [`new()`](Driver::new) to create the driver, then supply it to
+/// `embassy-usb`.
+pub struct Driver<'d> {
+    state: &'d DriverState,
+}
+
+impl<'d> Driver<'d> {
+    /// Create a USB driver
+    ///
+    /// This initializes the USB controller. Like the [`BusAdapter`](crate::BusAdapter),
+    /// the driver does not configure clocks or the PLL; make sure that they're
+    /// configured before calling `new()`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint memory, endpoint state, or driver state has already
+    /// been assigned to another USB driver.
+    pub fn new<const N: u8, const SIZE: usize, const EP_COUNT: usize, const TD_COUNT: usize>(
+        instances: crate::Instances<N>,
+        buffer: &'static crate::buffer::EndpointMemory<SIZE>,
+        state: &'static crate::state::EndpointState<EP_COUNT, TD_COUNT>,
+        driver_state: &'d DriverState,
+        speed: crate::Speed,
+    ) -> Self {
+        let mut usb = driver::Driver::new(instances, buffer, state);
+        usb.initialize(speed);
+        Self::with_driver(usb, driver_state)
+    }
+
+    /// Monitor VBUS, and automatically attach and detach from the bus
+    ///
+    /// The bus reports `PowerDetected` and `PowerRemoved` events as VBUS
+    /// changes. Without VBUS detection, the bus reports `PowerDetected` once.
+    /// See [`BusAdapter::enable_vbus_detection`](crate::BusAdapter::enable_vbus_detection)
+    /// for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` is the force reset timer, or the remote wakeup timer.
+    pub fn enable_vbus_detection(&mut self, timer: crate::gpt::Instance) {
+        self.state.with(|inner| {
+            assert!(
+                inner.remote_wakeup_timer != Some(timer),
+                "GPT already in use"
+            );
+            inner.driver().enable_vbus_detection(timer)
+        });
+    }
+
+    /// Also require the USB analog VBUS detector's `VBUS_VALID` status
+    ///
+    /// See [`BusAdapter::set_vbus_detect_status`](crate::BusAdapter::set_vbus_detect_status)
+    /// for more information.
+    pub fn set_vbus_detect_status(&mut self, status: &'static imxrt_ral::RORegister<u32>) {
+        self.state
+            .with(|inner| inner.driver().set_vbus_detect_status(status));
+    }
+
+    /// Let the bus force a reset, by detaching for `detach_us`
+    ///
+    /// See [`BusAdapter::set_force_reset_timer`](crate::BusAdapter::set_force_reset_timer)
+    /// for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` debounces VBUS, or if it's the remote wakeup timer.
+    pub fn set_force_reset_timer(&mut self, timer: crate::gpt::Instance, detach_us: u32) {
+        self.state.with(|inner| {
+            assert!(
+                inner.remote_wakeup_timer != Some(timer),
+                "GPT already in use"
+            );
+            inner.driver().set_force_reset_timer(timer, detach_us)
+        });
+    }
+
+    /// Let the bus signal remote wakeup, timed by `timer`
+    ///
+    /// `timer` times the resume signaling, so you must not use that GPT for
+    /// anything else. Without a remote wakeup timer, the bus' `remote_wakeup()`
+    /// returns `Unsupported`. See [`BusAdapter::remote_wakeup`](crate::BusAdapter::remote_wakeup)
+    /// for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `timer` debounces VBUS, or if it's the force reset timer.
+    pub fn set_remote_wakeup_timer(&mut self, timer: crate::gpt::Instance) {
+        self.state.with(|inner| {
+            assert!(!inner.driver().is_timer_used(timer), "GPT already in use");
+            inner.remote_wakeup_timer = Some(timer);
+        });
+    }
+
+    /// Add endpoint memory for the endpoints selected by `placement`
+    ///
+    /// Call this before you build the `embassy-usb` device. See
+    /// [`BusAdapter::add_endpoint_memory`](crate::BusAdapter::add_endpoint_memory)
+    /// for more information.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the endpoint memory has already been assigned to a USB driver.
+    pub fn add_endpoint_memory<const SIZE: usize>(
+        &mut self,
+        memory: &'static crate::EndpointMemory<SIZE>,
+        placement: crate::Placement,
+    ) -> Option<usize> {
+        self.state.with(|inner| {
+            let usb = inner.driver();
+            if !usb.has_endpoint_region_room() {
+                return None;
+            }
+            let allocator = memory
+                .allocator()
+                .expect("Endpoint memory already assigned");
+            usb.add_endpoint_region(allocator, placement)
+        })
+    }
+
+    /// Add a region of endpoint memory for the endpoints selected by `placement`
+    ///
+    /// See [`BusAdapter::add_endpoint_region`](crate::BusAdapter::add_endpoint_region)
+    /// for more information.
+    pub fn add_endpoint_region(
+        &mut self,
+        region: &'static mut [u8],
+        policy: crate::CachePolicy,
+        placement: crate::Placement,
+    ) -> Option<usize> {
+        self.state.with(|inner| {
+            let allocator = crate::buffer::Allocator::new(region, policy);
+            inner.driver().add_endpoint_region(allocator, placement)
+        })
+    }
+
+    fn with_driver(usb: driver::Driver, state: &'d DriverState) -> Self {
+        state.with(|inner| {
+            assert!(inner.driver.is_none(), "Driver state already assigned");
+            inner.driver = Some(usb);
+        });
+        Driver { state }
+    }
+
+    fn alloc_endpoint(
+        &mut self,
+        dir: UsbDirection,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<EndpointInfo, EndpointAllocError> {
+        let addr = self.state.with(|inner| {
+            inner.driver().alloc_ep(
+                dir,
+                ep_addr.map(usb_device_address),
+                usb_device_type(ep_type),
+                max_packet_size as usize,
+            )
+        });
+        let addr = addr.map_err(|_| EndpointAllocError)?;
+        Ok(EndpointInfo {
+            addr: embassy_address(addr),
+            ep_type,
+            max_packet_size,
+            interval_ms,
+        })
+    }
+}
+
+impl<'d> embassy_usb_driver::Driver<'d> for Driver<'d> {
+    type EndpointOut = EndpointOut<'d>;
+    type EndpointIn = EndpointIn<'d>;
+    type ControlPipe = ControlPipe<'d>;
+    type Bus = Bus<'d>;
+
+    fn alloc_endpoint_out(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointOut, EndpointAllocError> {
+        let info = self.alloc_endpoint(
+            UsbDirection::Out,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )?;
+        Ok(EndpointOut {
+            state: self.state,
+            info,
+        })
+    }
+
+    fn alloc_endpoint_in(
+        &mut self,
+        ep_type: EndpointType,
+        ep_addr: Option<EndpointAddress>,
+        max_packet_size: u16,
+        interval_ms: u8,
+    ) -> Result<Self::EndpointIn, EndpointAllocError> {
+        let info = self.alloc_endpoint(
+            UsbDirection::In,
+            ep_type,
+            ep_addr,
+            max_packet_size,
+            interval_ms,
+        )?;
+        Ok(EndpointIn {
+            state: self.state,
+            info,
+        })
+    }
+
+    /// # Panics
+    ///
+    /// Panics if there isn't enough endpoint memory for the control endpoints.
+    fn start(mut self, control_max_packet_size: u16) -> (Self::Bus, Self::ControlPipe) {
+        for dir in [Direction::Out, Direction::In] {
+            self.alloc_endpoint(
+                match dir {
+                    Direction::Out => UsbDirection::Out,
+                    Direction::In => UsbDirection::In,
+                },
+                EndpointType::Control,
+                Some(EndpointAddress::from_parts(0, dir)),
+                control_max_packet_size,
+                0,
+            )
+            .expect("Cannot allocate the control endpoints");
+        }
+        self.state.with(|inner| {
+            // Without VBUS detection, assume that power is present.
+            if inner.driver().is_vbus_present() != Some(false) {
+                inner.power = Some(Event::PowerDetected);
+            }
+        });
+        (
+            Bus { state: self.state },
+            ControlPipe {
+                state: self.state,
+                max_packet_size: control_max_packet_size as usize,
+            },
+        )
+    }
+}
+
+/// The USB bus
+///
+/// Produced when you `start()` the [`Driver`].
+pub struct Bus<'d> {
+    state: &'d DriverState,
+}
+
+impl Bus<'_> {
+    /// Returns the bus speed negotiated with the host
+    ///
+    /// See [`BusAdapter::negotiated_speed`](crate::BusAdapter::negotiated_speed)
+    /// for more information.
+    pub fn negotiated_speed(&self) -> Option<crate::BusSpeed> {
+        self.state.with(|inner| inner.driver().negotiated_speed())
+    }
+}
+
+impl embassy_usb_driver::Bus for Bus<'_> {
+    async fn enable(&mut self) {
+        self.state.with(|inner| {
+            let driver = inner.driver();
+            driver.set_interrupts(true);
+            driver.attach();
+        })
+    }
+
+    async fn disable(&mut self) {
+        self.state.with(|inner| {
+            let driver = inner.driver();
+            driver.detach();
+            driver.set_interrupts(false);
+        })
+    }
+
+    async fn poll(&mut self) -> Event {
+        poll_fn(|cx| {
+            self.state.with(|inner| {
+                if let Some(power) = inner.power.take() {
+                    Poll::Ready(power)
+                } else if core::mem::take(&mut inner.reset) {
+                    Poll::Ready(Event::Reset)
+                } else if core::mem::take(&mut inner.suspend) {
+                    Poll::Ready(Event::Suspend)
+                } else if core::mem::take(&mut inner.resume) {
+                    Poll::Ready(Event::Resume)
+                } else {
+                    inner.bus_waker.replace(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    fn endpoint_set_enabled(&mut self, ep_addr: EndpointAddress, enabled: bool) {
+        let addr = usb_device_address(ep_addr);
+        self.state.with(|inner| {
+            let driver = inner.driver();
+            if enabled {
+                driver.enable_ep(addr);
+            } else {
+                driver.disable_ep(addr);
+            }
+            inner.wake_endpoint(addr);
+        })
+    }
+
+    fn endpoint_set_stalled(&mut self, ep_addr: EndpointAddress, stalled: bool) {
+        let addr = usb_device_address(ep_addr);
+        self.state.with(|inner| {
+            let driver = inner.driver();
+            if driver.is_allocated(addr) {
+                driver.ep_stall(stalled, addr);
+            }
+        })
+    }
+
+    fn endpoint_is_stalled(&mut self, ep_addr: EndpointAddress) -> bool {
+        let addr = usb_device_address(ep_addr);
+        self.state.with(|inner| {
+            let driver = inner.driver();
+            driver.is_allocated(addr) && driver.is_ep_stalled(addr)
+        })
+    }
+
+    fn force_reset(&mut self) -> Result<(), Unsupported> {
+        self.state.with(|inner| {
+            inner.driver().force_reset().map_err(|_| Unsupported)?;
+            inner.wake_endpoints();
+            Ok(())
+        })
+    }
+
+    /// Returns `Unsupported` if there's no remote wakeup timer, or if the bus
+    /// isn't suspended. The bus reports `Resume` once the signaling ends.
+    async fn remote_wakeup(&mut self) -> Result<(), Unsupported> {
+        self.state.with(|inner| {
+            let timer = inner.remote_wakeup_timer.ok_or(Unsupported)?;
+            inner.driver().remote_wakeup(timer).map_err(|_| Unsupported)
+        })
+    }
+}
+
+/// An OUT endpoint
+pub struct EndpointOut<'d> {
+    state: &'d DriverState,
+    info: EndpointInfo,
+}
+
+impl embassy_usb_driver::Endpoint for EndpointOut<'_> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        wait_enabled(self.state, &self.info).await
+    }
+}
+
+impl embassy_usb_driver::EndpointOut for EndpointOut<'_> {
+    /// Read a packet
+    ///
+    /// Returns `Disabled` if the endpoint is disabled, or if the endpoint's
+    /// transfer failed.
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let addr = usb_device_address(self.info.addr);
+        poll_fn(|cx| {
+            self.state.with(|inner| {
+                let driver = inner.driver();
+                if !driver.is_ep_enabled(addr) {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                if driver.ep_completed_len(addr).unwrap_or(0) > buf.len() {
+                    return Poll::Ready(Err(EndpointError::BufferOverflow));
+                }
+                match driver.ep_read(buf, addr) {
+                    Ok(read) => Poll::Ready(Ok(read)),
+                    Err(UsbError::WouldBlock) => {
+                        inner.register_endpoint(addr, cx.waker());
+                        Poll::Pending
+                    }
+                    Err(_) => Poll::Ready(Err(EndpointError::Disabled)),
+                }
+            })
+        })
+        .await
+    }
+}
+
+/// An IN endpoint
+pub struct EndpointIn<'d> {
+    state: &'d DriverState,
+    info: EndpointInfo,
+}
+
+impl embassy_usb_driver::Endpoint for EndpointIn<'_> {
+    fn info(&self) -> &EndpointInfo {
+        &self.info
+    }
+
+    async fn wait_enabled(&mut self) {
+        wait_enabled(self.state, &self.info).await
+    }
+}
+
+impl embassy_usb_driver::EndpointIn for EndpointIn<'_> {
+    /// Write a packet
+    ///
+    /// Waits until the endpoint has a free transfer descriptor. Returns
+    /// `Disabled` if the endpoint is disabled, or if the endpoint's transfer
+    /// failed.
+    async fn write(&mut self, buf: &[u8]) -> Result<(), EndpointError> {
+        if buf.len() > self.info.max_packet_size as usize {
+            return Err(EndpointError::BufferOverflow);
+        }
+        let addr = usb_device_address(self.info.addr);
+        poll_fn(|cx| {
+            self.state.with(|inner| {
+                let driver = inner.driver();
+                if !driver.is_ep_enabled(addr) {
+                    return Poll::Ready(Err(EndpointError::Disabled));
+                }
+                match driver.ep_write(buf, addr) {
+                    Ok(_) => Poll::Ready(Ok(())),
+                    Err(UsbError::WouldBlock) => {
+                        inner.register_endpoint(addr, cx.waker());
+                        Poll::Pending
+                    }
+                    Err(_) => Poll::Ready(Err(EndpointError::Disabled)),
+                }
+            })
+        })
+        .await
+    }
+}
+
+async fn wait_enabled(state: &DriverState, info: &EndpointInfo) {
+    let addr = usb_device_address(info.addr);
+    poll_fn(|cx| {
+        state.with(|inner| {
+            if inner.driver().is_ep_enabled(addr) {
+                Poll::Ready(())
+            } else {
+                inner.register_endpoint(addr, cx.waker());
+                Poll::Pending
+            }
+        })
+    })
+    .await
+}
+
+/// The control pipe
+///
+/// Produced when you `start()` the [`Driver`].
+pub struct ControlPipe<'d> {
+    state: &'d DriverState,
+    max_packet_size: usize,
+}
+
+impl ControlPipe<'_> {
+    /// Wait until EP0 IN accepts the data
+    async fn write(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        poll_fn(|cx| {
+            self.state
+                .with(|inner| match inner.driver().ctrl0_write(data) {
+                    Ok(_) => Poll::Ready(Ok(())),
+                    Err(UsbError::WouldBlock) => {
+                        inner.control_waker.replace(cx.waker().clone());
+                        Poll::Pending
+                    }
+                    Err(_) => Poll::Ready(Err(EndpointError::Disabled)),
+                })
+        })
+        .await
+    }
+}
+
+impl embassy_usb_driver::ControlPipe for ControlPipe<'_> {
+    fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    async fn setup(&mut self) -> [u8; 8] {
+        poll_fn(|cx| {
+            self.state.with(|inner| match inner.driver().ctrl0_setup() {
+                Some(setup) => Poll::Ready(setup),
+                None => {
+                    inner.control_waker.replace(cx.waker().clone());
+                    Poll::Pending
+                }
+            })
+        })
+        .await
+    }
+
+    async fn data_out(
+        &mut self,
+        buf: &mut [u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<usize, EndpointError> {
+        poll_fn(|cx| {
+            self.state
+                .with(|inner| match inner.driver().ctrl0_read_data(buf) {
+                    Ok(read) => Poll::Ready(Ok(read)),
+                    Err(UsbError::WouldBlock) => {
+                        inner.control_waker.replace(cx.waker().clone());
+                        Poll::Pending
+                    }
+                    Err(_) => Poll::Ready(Err(EndpointError::Disabled)),
+                })
+        })
+        .await
+    }
+
+    async fn data_in(
+        &mut self,
+        data: &[u8],
+        _first: bool,
+        _last: bool,
+    ) -> Result<(), EndpointError> {
+        if data.len() > self.max_packet_size {
+            return Err(EndpointError::BufferOverflow);
+        }
+        // ctrl0_write() primes EP0 OUT for the status phase.
+        self.write(data).await
+    }
+
+    async fn accept(&mut self) {
+        // Status phase for a control OUT, or a control without data.
+        self.write(&[]).await.ok();
+    }
+
+    async fn reject(&mut self) {
+        self.state.with(|inner| {
+            let driver = inner.driver();
+            driver.ep_stall(
+                true,
+                usb_device_address(EndpointAddress::from_parts(0, Direction::In)),
+            );
+            driver.ep_stall(
+                true,
+                usb_device_address(EndpointAddress::from_parts(0, Direction::Out)),
+            );
+        })
+    }
+
+    async fn accept_set_address(&mut self, addr: u8) {
+        // USBADRA defers the address change until the status phase completes.
+        self.state.with(|inner| inner.driver().set_address(addr));
+        self.accept().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{Driver, DriverState};
+    use crate::{buffer::EndpointMemory, ral, state::EndpointState};
+    use core::{
+        future::Future,
+        pin::pin,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::{Context, Poll, Waker},
+    };
+    use embassy_usb_driver::{
+        Bus as _, Direction, Driver as _, Endpoint as _, EndpointAddress, EndpointType, Event,
+        Unsupported,
+    };
+    use std::{boxed::Box, sync::Arc, task::Wake};
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    impl CountingWaker {
+        fn count(&self) -> usize {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Create a driver that uses simulated registers.
+    ///
+    /// Skips the controller initialization, which waits on the controller.
+    fn driver() -> (&'static ral::sim::Registers, Driver<'static>) {
+        let registers = ral::sim::Registers::leak();
+        let memory: &'static EndpointMemory<1024> = Box::leak(Box::new(EndpointMemory::new()));
+        let state: &'static EndpointState = Box::leak(Box::new(EndpointState::max_endpoints()));
+        let driver_state: &'static DriverState = Box::leak(Box::new(DriverState::new()));
+        let usb = crate::driver::Driver::new(registers.instances(), memory, state);
+        (registers, Driver::with_driver(usb, driver_state))
+    }
+
+    #[test]
+    #[should_panic]
+    fn driver_state_assigned_once() {
+        let (registers, driver) = driver();
+        let memory: &'static EndpointMemory<64> = Box::leak(Box::new(EndpointMemory::new()));
+        let state: &'static EndpointState = Box::leak(Box::new(EndpointState::max_endpoints()));
+        let usb = crate::driver::Driver::new(registers.instances(), memory, state);
+        Driver::with_driver(usb, driver.state);
+    }
+
+    #[test]
+    fn allocate_endpoints() {
+        let (_, mut driver) = driver();
+
+        let ep = driver
+            .alloc_endpoint_in(EndpointType::Bulk, None, 64, 0)
+            .unwrap();
+        assert_eq!(
+            ep.info().addr,
+            EndpointAddress::from_parts(1, Direction::In)
+        );
+        assert_eq!(ep.info().max_packet_size, 64);
+
+        let ep = driver
+            .alloc_endpoint_out(EndpointType::Bulk, None, 64, 0)
+            .unwrap();
+        assert_eq!(
+            ep.info().addr,
+            EndpointAddress::from_parts(1, Direction::Out)
+        );
+
+        let addr = EndpointAddress::from_parts(3, Direction::In);
+        let ep = driver
+            .alloc_endpoint_in(EndpointType::Interrupt, Some(addr), 8, 10)
+            .unwrap();
+        assert_eq!(ep.info().addr, addr);
+        assert_eq!(ep.info().interval_ms, 10);
+
+        // Already allocated.
+        assert!(
+            driver
+                .alloc_endpoint_in(EndpointType::Interrupt, Some(addr), 8, 10)
+                .is_err()
+        );
+        // Out of memory.
+        assert!(
+            driver
+                .alloc_endpoint_out(EndpointType::Bulk, None, 1024, 0)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn power_detected_first() {
+        let (_, driver) = driver();
+        let (mut bus, _) = driver.start(64);
+
+        let waker = Arc::new(CountingWaker::default());
+        let waker = Waker::from(waker);
+        let mut cx = Context::from_waker(&waker);
+
+        assert_eq!(
+            pin!(bus.poll()).poll(&mut cx),
+            Poll::Ready(Event::PowerDetected)
+        );
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Pending);
+    }
+
+    #[test]
+    fn stall_endpoint() {
+        let (registers, mut driver) = driver();
+        let addr = EndpointAddress::from_parts(2, Direction::In);
+        driver
+            .alloc_endpoint_in(EndpointType::Bulk, Some(addr), 64, 0)
+            .unwrap();
+        let (mut bus, _) = driver.start(64);
+
+        assert!(!bus.endpoint_is_stalled(addr));
+        bus.endpoint_set_stalled(addr, true);
+        assert!(bus.endpoint_is_stalled(addr));
+        assert!(ral::read_reg!(
+            ral::endpoint_control,
+            &ral::endpoint_control::EndptCtrl {
+                ENDPTCTRL: &registers.usb.ENDPTCTRL[1]
+            },
+            ENDPTCTRL,
+            TXS == 1
+        ));
+
+        // Unstalling resets the data toggle.
+        ral::modify_reg!(ral::usb, &registers.usb, ENDPTCTRL[1], TXR: 0);
+        bus.endpoint_set_stalled(addr, false);
+        assert!(!bus.endpoint_is_stalled(addr));
+        assert_eq!(
+            ral::read_reg!(ral::usb, &registers.usb, ENDPTCTRL[1], TXR),
+            1
+        );
+
+        // Unallocated endpoints are never stalled.
+        let unallocated = EndpointAddress::from_parts(4, Direction::In);
+        bus.endpoint_set_stalled(unallocated, true);
+        assert!(!bus.endpoint_is_stalled(unallocated));
+    }
+
+    #[test]
+    fn wake_on_enable() {
+        let (_, mut driver) = driver();
+        let mut ep = driver
+            .alloc_endpoint_in(EndpointType::Bulk, None, 64, 0)
+            .unwrap();
+        let addr = ep.info().addr;
+        let (mut bus, _) = driver.start(64);
+
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut wait = pin!(ep.wait_enabled());
+        assert_eq!(wait.as_mut().poll(&mut cx), Poll::Pending);
+        assert_eq!(counter.count(), 0);
+
+        bus.endpoint_set_enabled(addr, true);
+        assert_eq!(counter.count(), 1);
+        assert_eq!(wait.as_mut().poll(&mut cx), Poll::Ready(()));
+    }
+
+    #[test]
+    fn wake_on_transfer_complete() {
+        let (registers, mut driver) = driver();
+        let mut ep = driver
+            .alloc_endpoint_in(EndpointType::Bulk, None, 64, 0)
+            .unwrap();
+        let addr = ep.info().addr;
+        let state = driver.state;
+        let (mut bus, _) = driver.start(64);
+        bus.endpoint_set_enabled(addr, true);
+
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // Nothing to wait on; the endpoint is enabled.
+        assert_eq!(pin!(ep.wait_enabled()).poll(&mut cx), Poll::Ready(()));
+        state.with(|inner| inner.register_endpoint(super::usb_device_address(addr), &waker));
+
+        // Another endpoint's completion doesn't wake this endpoint.
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, UI: 1);
+        ral::write_reg!(ral::usb, &registers.usb, ENDPTCOMPLETE, ETCE: 1 << 2);
+        state.on_interrupt();
+        assert_eq!(counter.count(), 0);
+
+        ral::write_reg!(ral::usb, &registers.usb, ENDPTCOMPLETE, ETCE: 1 << 1);
+        state.on_interrupt();
+        assert_eq!(counter.count(), 1);
+    }
+
+    #[test]
+    fn power_detected_with_data() {
+        use crate::gpt;
+
+        let (registers, mut driver) = driver();
+        driver.enable_vbus_detection(gpt::Instance::Gpt1);
+        let mut ep = driver
+            .alloc_endpoint_out(EndpointType::Bulk, None, 64, 0)
+            .unwrap();
+        let addr = ep.info().addr;
+        let state = driver.state;
+        let (mut bus, _) = driver.start(64);
+        bus.endpoint_set_enabled(addr, true);
+
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert_eq!(pin!(ep.wait_enabled()).poll(&mut cx), Poll::Ready(()));
+        state.with(|inner| inner.register_endpoint(super::usb_device_address(addr), &waker));
+
+        // VBUS debounces in the same interrupt that reports received data.
+        ral::write_reg!(ral::usb, &registers.usb, OTGSC, BSV: 1);
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, TI1: 1);
+        state.with(|inner| inner.driver().simulate_ep_out_pending(1 << 1));
+        state.on_interrupt();
+        assert_eq!(counter.count(), 1);
+        assert_eq!(
+            pin!(bus.poll()).poll(&mut cx),
+            Poll::Ready(Event::PowerDetected)
+        );
+    }
+
+    #[test]
+    fn suspend_resume() {
+        let (registers, driver) = driver();
+        let state = driver.state;
+        let (mut bus, _) = driver.start(64);
+
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+        assert_eq!(
+            pin!(bus.poll()).poll(&mut cx),
+            Poll::Ready(Event::PowerDetected)
+        );
+
+        // The bus powers down the PHY when it reports a suspend, and
+        // restores the PHY when it reports a resume.
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, SLI: 1);
+        ral::write_reg!(ral::usb, &registers.usb, PORTSC1, SUSP: 1);
+        state.on_interrupt();
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Ready(Event::Suspend));
+        assert!(ral::read_reg!(ral::usb, &registers.usb, PORTSC1, PHCD == 1));
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Pending);
+
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, PCI: 1);
+        ral::modify_reg!(ral::usb, &registers.usb, PORTSC1, SUSP: 0);
+        state.on_interrupt();
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Ready(Event::Resume));
+        assert!(ral::read_reg!(ral::usb, &registers.usb, PORTSC1, PHCD == 0));
+    }
+
+    #[test]
+    fn remote_wakeup_unsupported() {
+        let (registers, driver) = driver();
+        let state = driver.state;
+        let (mut bus, _) = driver.start(64);
+
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, SLI: 1);
+        ral::write_reg!(ral::usb, &registers.usb, PORTSC1, SUSP: 1);
+        state.on_interrupt();
+
+        // Suspended, but there's no timer.
+        assert_eq!(
+            pin!(bus.remote_wakeup()).poll(&mut cx),
+            Poll::Ready(Err(Unsupported))
+        );
+    }
+
+    #[test]
+    fn remote_wakeup() {
+        let (registers, mut driver) = driver();
+        driver.set_remote_wakeup_timer(crate::gpt::Instance::Gpt0);
+        let state = driver.state;
+        let (mut bus, _) = driver.start(64);
+
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+        assert_eq!(
+            pin!(bus.poll()).poll(&mut cx),
+            Poll::Ready(Event::PowerDetected)
+        );
+
+        // Not suspended.
+        assert_eq!(
+            pin!(bus.remote_wakeup()).poll(&mut cx),
+            Poll::Ready(Err(Unsupported))
+        );
+
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, SLI: 1);
+        ral::write_reg!(ral::usb, &registers.usb, PORTSC1, SUSP: 1);
+        state.on_interrupt();
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Ready(Event::Suspend));
+        assert_eq!(pin!(bus.remote_wakeup()).poll(&mut cx), Poll::Ready(Ok(())));
+        assert!(ral::read_reg!(ral::usb, &registers.usb, PORTSC1, FPR == 1));
+
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, TI0: 1);
+        state.on_interrupt();
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Ready(Event::Resume));
+        assert!(ral::read_reg!(ral::usb, &registers.usb, PORTSC1, FPR == 0));
+    }
+
+    #[test]
+    fn vbus_detection() {
+        use crate::gpt;
+
+        let (registers, mut driver) = driver();
+        driver.enable_vbus_detection(gpt::Instance::Gpt1);
+        let state = driver.state;
+        let (mut bus, _) = driver.start(64);
+
+        let waker = Waker::noop();
+        let mut cx = Context::from_waker(waker);
+        // No VBUS, so the bus doesn't report power.
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Pending);
+        assert_eq!(pin!(bus.enable()).poll(&mut cx), Poll::Ready(()));
+
+        ral::write_reg!(ral::usb, &registers.usb, OTGSC, BSV: 1);
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, TI1: 1);
+        state.on_interrupt();
+        assert_eq!(
+            pin!(bus.poll()).poll(&mut cx),
+            Poll::Ready(Event::PowerDetected)
+        );
+
+        // The bus powers down the PHY when it reports power removal.
+        ral::write_reg!(ral::usb, &registers.usb, OTGSC, BSV: 0);
+        state.on_interrupt();
+        assert_eq!(
+            pin!(bus.poll()).poll(&mut cx),
+            Poll::Ready(Event::PowerRemoved)
+        );
+        assert!(ral::read_reg!(ral::usb, &registers.usb, PORTSC1, PHCD == 1));
+        assert_eq!(pin!(bus.poll()).poll(&mut cx), Poll::Pending);
+    }
+
+    #[test]
+    #[should_panic]
+    fn remote_wakeup_timer_in_use() {
+        let (_, mut driver) = driver();
+        driver.enable_vbus_detection(crate::gpt::Instance::Gpt1);
+        driver.set_remote_wakeup_timer(crate::gpt::Instance::Gpt1);
+    }
+
+    #[test]
+    fn negotiated_speed() {
+        use crate::BusSpeed;
+
+        let (registers, driver) = driver();
+        let state = driver.state;
+        let (bus, _) = driver.start(64);
+        assert_eq!(bus.negotiated_speed(), None);
+
+        ral::write_reg!(ral::usb, &registers.usb, PORTSC1, PSPD: 2);
+        ral::write_reg!(ral::usb, &registers.usb, USBSTS, PCI: 1);
+        state.on_interrupt();
+        assert_eq!(bus.negotiated_speed(), Some(BusSpeed::High));
+    }
+}
@@ -24,49 +24,265 @@
 
 use crate::{
     buffer::Buffer,
+    cache::CachePolicy,
     qh::Qh,
     ral,
     ral::endpoint_control,
-    td::{Status, Td},
+    td::{self, Status, Td},
 };
 use usb_device::{
     UsbDirection, UsbError,
     endpoint::{EndpointAddress, EndpointType},
 };
 
+/// An error in an isochronous transfer
+///
+/// Isochronous endpoints don't halt when a transfer fails. Instead, the
+/// driver drops the failed transfer, and records the error.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IsochronousError {
+    /// The transfer didn't happen in its (micro)frame.
+    MissedFrame,
+    /// The controller couldn't move data to or from memory in time.
+    ///
+    /// This is an overrun for an OUT endpoint, or an underrun for an IN
+    /// endpoint.
+    DataBuffer,
+}
+
+/// The error bits of a failed transfer
+///
+/// A transfer can fail with more than one error. See
+/// [`BusAdapter::endpoint_status`](crate::BusAdapter::endpoint_status).
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct TransferError {
+    /// A transaction error, like a CRC error, a timeout, or a bad PID.
+    ///
+    /// For an isochronous endpoint, this is a missed (micro)frame.
+    pub transaction: bool,
+    /// The controller couldn't move data to or from memory in time.
+    ///
+    /// This is an overrun for an OUT endpoint, or an underrun for an IN
+    /// endpoint.
+    pub data_buffer: bool,
+    /// The controller halted the endpoint.
+    pub halted: bool,
+}
+
+impl TransferError {
+    /// Returns the error bits of a TD status, or `None` if there's no error
+    fn from_status(status: Status) -> Option<Self> {
+        let error = TransferError {
+            transaction: status.contains(Status::TRANSACTION_ERROR),
+            data_buffer: status.contains(Status::DATA_BUFFER_ERROR),
+            halted: status.contains(Status::HALTED),
+        };
+        (error != TransferError::default()).then_some(error)
+    }
+}
+
+/// The number of failed transfers, by error
+///
+/// A transfer with more than one error increments more than one counter.
+/// Counters wrap on overflow.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ErrorCounts {
+    /// Transfers with a transaction error.
+    pub transaction: u32,
+    /// Transfers with a data buffer error.
+    pub data_buffer: u32,
+    /// Transfers that halted the endpoint.
+    pub halted: u32,
+}
+
+impl ErrorCounts {
+    /// Count the errors of one failed transfer
+    fn count(&mut self, error: TransferError) {
+        self.transaction = self.transaction.wrapping_add(error.transaction as u32);
+        self.data_buffer = self.data_buffer.wrapping_add(error.data_buffer as u32);
+        self.halted = self.halted.wrapping_add(error.halted as u32);
+    }
+}
+
+/// Split a `wMaxPacketSize` value into the packet length, and the number of
+/// packets per (micro)frame
+///
+/// Only isochronous endpoints can move more than one packet per (micro)frame.
+/// For all other endpoints, this returns the max packet size, and one packet.
+pub fn max_packet_parts(kind: EndpointType, max_packet_size: usize) -> (usize, usize) {
+    if let EndpointType::Isochronous { .. } = kind {
+        let mult = ((max_packet_size >> 11) & 0b11) + 1;
+        (max_packet_size & 0x7FF, mult.min(3))
+    } else {
+        (max_packet_size, 1)
+    }
+}
+
 /// A USB endpoint
+///
+/// The endpoint's TDs form a ring. Transfers are scheduled at the tail
+/// of the ring, and retired from the head in the order they were scheduled.
+/// Each TD has its own, equally-sized portion of the endpoint buffer.
 pub struct Endpoint {
     address: EndpointAddress,
     qh: &'static mut Qh,
-    td: &'static mut Td,
+    tds: &'static mut [Td],
+    /// Index of the oldest scheduled TD.
+    head: usize,
+    /// Number of scheduled TDs that are not yet retired.
+    scheduled: usize,
+    /// The maximum number of bytes moved by one TD.
+    transfer_len: usize,
     buffer: Buffer,
     kind: EndpointType,
+    /// The most recent isochronous transfer error.
+    isochronous_error: Option<IsochronousError>,
+    /// D-cache maintenance policy for the QH and TDs.
+    cache_policy: CachePolicy,
+    /// Index of the TD whose buffer is lent out.
+    ///
+    /// For an OUT endpoint, this is the oldest transfer. It stays scheduled,
+    /// so that the endpoint doesn't receive into its buffer. For an IN endpoint,
+    /// this is the next transfer, which isn't scheduled until it's committed.
+    lent: Option<usize>,
+    /// Set after priming, until the controller finishes the prime.
+    priming: bool,
+    /// For an isochronous endpoint, the TD that waits for the next (micro)frame
+    /// boundary before it's primed, and the FRINDEX value when it was scheduled.
+    deferred_prime: Option<(usize, u32)>,
+    /// For an isochronous endpoint, the FRINDEX value just after the (micro)frame
+    /// boundary that primed the endpoint.
+    prime_frindex: Option<u32>,
+    /// Errors of all failed transfers.
+    error_counts: ErrorCounts,
+    /// Set once the oldest transfer's errors are counted.
+    error_counted: bool,
 }
 
 impl Endpoint {
     pub fn new(
         address: EndpointAddress,
         qh: &'static mut Qh,
-        td: &'static mut Td,
+        tds: &'static mut [Td],
         buffer: Buffer,
         kind: EndpointType,
+        cache_policy: CachePolicy,
     ) -> Self {
-        let max_packet_size = buffer.len();
+        let max_packet_size = buffer.len() / tds.len();
         qh.set_zero_length_termination(false);
         qh.set_max_packet_len(max_packet_size);
         qh.set_interrupt_on_setup(
             EndpointType::Control == kind && address.direction() == UsbDirection::Out,
         );
 
-        td.set_terminate();
-        td.clear_status();
+        for td in tds.iter_mut() {
+            td.set_terminate();
+            td.clear_status();
+        }
 
         Endpoint {
             address,
             qh,
-            td,
+            tds,
+            head: 0,
+            scheduled: 0,
+            transfer_len: max_packet_size,
             buffer,
             kind,
+            isochronous_error: None,
+            cache_policy,
+            lent: None,
+            priming: false,
+            deferred_prime: None,
+            prime_frindex: None,
+            error_counts: ErrorCounts::default(),
+            error_counted: false,
+        }
+    }
+
+    /// Set the maximum packet length
+    ///
+    /// By default, the maximum packet length is the size of each TD's buffer.
+    /// If a TD's buffer holds more than one packet, the controller splits each
+    /// transfer into packets of `max_packet_len`. The transfer length is the
+    /// largest multiple of `max_packet_len` that fits in the TD's buffer.
+    ///
+    /// For isochronous endpoints, `max_packet_len` is the `wMaxPacketSize`
+    /// value, including the number of additional transactions per microframe.
+    /// Each transfer moves one (micro)frame of data.
+    pub fn set_max_packet_len(&mut self, max_packet_len: usize) {
+        let td_buffer_len = (self.buffer.len() / self.tds.len()).min(td::MAX_TRANSFER_LEN);
+        let (max_packet_len, mult) = max_packet_parts(self.kind, max_packet_len);
+        let max_packet_len = max_packet_len.min(td_buffer_len);
+        self.qh.set_max_packet_len(max_packet_len);
+
+        if self.is_isochronous() {
+            self.qh.set_mult(mult);
+            self.transfer_len = td_buffer_len.min(max_packet_len * mult);
+        } else {
+            self.qh.set_mult(0);
+            self.transfer_len =
+                td_buffer_len - td_buffer_len.checked_rem(max_packet_len).unwrap_or(0);
+        }
+    }
+
+    /// Limit the transfer length to `max_transfer_len`
+    ///
+    /// A TD's buffer can be larger than one transfer, like when it's padded to
+    /// keep the next TD's buffer aligned.
+    pub fn limit_transfer_len(&mut self, max_transfer_len: usize) {
+        self.transfer_len = self.transfer_len.min(max_transfer_len);
+    }
+
+    /// Returns the endpoint buffer
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffer
+    }
+
+    /// Returns the endpoint type
+    pub fn kind(&self) -> EndpointType {
+        self.kind
+    }
+
+    /// Release the endpoint, returning its buffer
+    ///
+    /// The controller must not be using the endpoint's QH, TDs, or buffer.
+    pub fn into_buffer(self) -> Buffer {
+        self.buffer
+    }
+
+    /// Indicates if this is an isochronous endpoint
+    fn is_isochronous(&self) -> bool {
+        matches!(self.kind, EndpointType::Isochronous { .. })
+    }
+
+    /// Returns the maximum number of bytes that one transfer can move
+    pub fn transfer_len(&self) -> usize {
+        self.transfer_len
+    }
+
+    /// Returns the portion of the endpoint buffer used by the TD at `index`
+    ///
+    /// Neighboring TD buffers may share a D-cache line. That's OK, since the
+    /// CPU and the controller move data in the same direction for every TD.
+    fn td_buffer(&self, index: usize) -> Buffer {
+        let len = self.buffer.len() / self.tds.len();
+        // Safety: the endpoint owns the buffer, and it only uses TD buffers
+        // for the duration of a `&mut self` call.
+        unsafe { self.buffer.subrange(index * len, len) }
+    }
+
+    /// Returns the index of the next TD to schedule
+    fn tail(&self) -> usize {
+        (self.head + self.scheduled) % self.tds.len()
+    }
+
+    /// Returns the bit that represents this endpoint in ENDPTPRIME,
+    /// ENDPTSTAT, and similar registers
+    pub fn register_bit(&self) -> u32 {
+        match self.address.direction() {
+            UsbDirection::In => 1 << (16 + self.address.index()),
+            UsbDirection::Out => 1 << self.address.index(),
         }
     }
 
@@ -75,32 +291,227 @@ impl Endpoint {
         self.qh.set_zero_length_termination(true);
     }
 
-    /// Indicates if the transfer descriptor is active
+    /// Indicates if the endpoint is primed, or if the controller is priming it
     pub fn is_primed(&self, usb: &ral::AnyUsbInstance) -> bool {
-        (match self.address.direction() {
-            UsbDirection::In => ral::read_reg!(ral::usb, usb, ENDPTSTAT, ETBR),
-            UsbDirection::Out => ral::read_reg!(ral::usb, usb, ENDPTSTAT, ERBR),
-        } & (1 << self.address.index()))
+        let bit = self.register_bit();
+        (ral::read_reg!(ral::usb, usb, ENDPTPRIME) | ral::read_reg!(ral::usb, usb, ENDPTSTAT)) & bit
             != 0
     }
 
+    /// Confirm the most recent prime, once the controller finishes it
+    ///
+    /// The controller clears the ENDPTPRIME bit when it finishes priming, and sets
+    /// the ENDPTSTAT bit if the endpoint is primed. A short transfer can complete,
+    /// and clear the ENDPTSTAT bit, before the driver observes it. So if neither
+    /// bit is set, the driver checks the scheduled transfers: if one is still active,
+    /// the prime failed, and this primes the endpoint again.
+    ///
+    /// Control endpoints aren't primed again, since a setup packet cancels their
+    /// primes. Returns `true` if there's no prime in progress.
+    pub fn confirm_prime(&mut self, usb: &ral::AnyUsbInstance) -> bool {
+        if !self.priming {
+            return true;
+        }
+        let bit = self.register_bit();
+        if ral::read_reg!(ral::usb, usb, ENDPTPRIME) & bit != 0 {
+            return false;
+        }
+        self.priming = false;
+        if ral::read_reg!(ral::usb, usb, ENDPTSTAT) & bit != 0 || self.address.index() == 0 {
+            return true;
+        }
+        match self.first_active() {
+            Some(index) => {
+                debug!(
+                    "EP{=usize} {} PRIME AGAIN",
+                    self.address.index(),
+                    self.address.direction()
+                );
+                self.prime(usb, index);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Returns the index of the oldest scheduled TD that's still active
+    fn first_active(&self) -> Option<usize> {
+        (0..self.scheduled)
+            .map(|offset| (self.head + offset) % self.tds.len())
+            .find(|&index| {
+                let td = &self.tds[index];
+                td.clean_invalidate_dcache(self.cache_policy);
+                td.status().contains(Status::ACTIVE)
+            })
+    }
+
+    /// Point the QH at no TD
+    ///
+    /// A prime that's still in progress finds no transfer. Only call this when
+    /// the controller isn't processing the endpoint's TDs, or just before a flush.
+    pub fn terminate(&mut self) {
+        self.qh.overlay_mut().set_terminate();
+        self.qh.clean_invalidate_dcache(self.cache_policy);
+    }
+
+    /// Indicates if every TD has a scheduled transfer
+    ///
+    /// When this returns `true`, you cannot schedule another transfer until
+    /// you retire the oldest transfer.
+    pub fn is_full(&self) -> bool {
+        self.scheduled == self.tds.len()
+    }
+
+    /// Indicates if the oldest scheduled transfer has completed
+    ///
+    /// Returns `false` if there are no scheduled transfers.
+    pub fn is_complete(&self) -> bool {
+        if self.scheduled == 0 {
+            return false;
+        }
+        let td = &self.tds[self.head];
+        td.clean_invalidate_dcache(self.cache_policy);
+        !td.status().contains(Status::ACTIVE)
+    }
+
+    /// Returns the number of bytes moved by the oldest scheduled transfer,
+    /// if it completed
+    #[cfg(feature = "embassy")]
+    pub fn completed_len(&self) -> Option<usize> {
+        self.is_complete()
+            .then(|| self.tds[self.head].bytes_transferred())
+    }
+
+    /// Retire the oldest scheduled transfer, if it completed
+    ///
+    /// Returns `true` if a transfer was retired. If the transfer was an
+    /// isochronous transfer that failed, this records the error.
+    pub fn retire(&mut self) -> bool {
+        let complete = self.is_complete();
+        if complete {
+            if let Some(error) = self.isochronous_status() {
+                self.isochronous_error = Some(error);
+            }
+            self.count_errors();
+            self.head = (self.head + 1) % self.tds.len();
+            self.scheduled -= 1;
+            self.error_counted = false;
+        }
+        complete
+    }
+
+    /// Returns the error of the oldest isochronous transfer, if it failed
+    fn isochronous_status(&self) -> Option<IsochronousError> {
+        if !self.is_isochronous() || self.scheduled == 0 {
+            return None;
+        }
+        let status = self.tds[self.head].status();
+        if status.contains(Status::TRANSACTION_ERROR) {
+            Some(IsochronousError::MissedFrame)
+        } else if status.contains(Status::DATA_BUFFER_ERROR) {
+            Some(IsochronousError::DataBuffer)
+        } else {
+            None
+        }
+    }
+
+    /// Retire the oldest isochronous transfers that completed with an error
+    ///
+    /// Returns `true` if any transfer was retired. Does nothing for other
+    /// endpoints.
+    pub fn retire_failed(&mut self) -> bool {
+        let mut retired = false;
+        while self.is_complete() && self.isochronous_status().is_some() {
+            retired |= self.retire();
+        }
+        retired
+    }
+
+    /// Take the most recent isochronous transfer error
+    pub fn take_isochronous_error(&mut self) -> Option<IsochronousError> {
+        self.isochronous_error.take()
+    }
+
+    /// Retire all completed transfers, in order
+    ///
+    /// Stops at the first transfer that's still active.
+    pub fn retire_completed(&mut self) {
+        while self.retire() {}
+    }
+
+    /// Forget all scheduled transfers
+    ///
+    /// A lent transfer keeps its TD until it's released. Only call this when
+    /// the controller isn't processing any of this endpoint's TDs, like when the
+    /// endpoint isn't primed, or after a flush.
+    pub fn reset_transfers(&mut self) {
+        self.error_counted = false;
+        self.deferred_prime = None;
+        if let Some(index) = self.lent {
+            self.head = index;
+            self.scheduled = (self.address.direction() == UsbDirection::Out) as usize;
+        } else {
+            self.head = 0;
+            self.scheduled = 0;
+        }
+    }
+
     /// Check for any transfer status, which is signaled through
     /// an error
-    pub fn check_errors(&self) -> Result<(), UsbError> {
-        let status = self.td.status();
-        if status.contains(Status::TRANSACTION_ERROR)
-            | status.contains(Status::DATA_BUFFER_ERROR)
-            | status.contains(Status::HALTED)
-        {
-            Err(UsbError::InvalidState)
-        } else {
+    ///
+    /// Checks the status of the oldest scheduled transfer. Isochronous
+    /// transfer errors aren't signaled here; see [`retire_failed()`](Endpoint::retire_failed).
+    ///
+    /// Any error is `InvalidState`. See [`transfer_error()`](Endpoint::transfer_error)
+    /// to learn which errors occurred.
+    pub fn check_errors(&mut self) -> Result<(), UsbError> {
+        let Some(error) = self.transfer_error() else {
+            return Ok(());
+        };
+        self.count_errors();
+        if self.is_isochronous() && !error.halted {
             Ok(())
+        } else {
+            Err(UsbError::InvalidState)
         }
     }
 
+    /// Returns the errors of the oldest scheduled transfer, if it failed
+    pub fn transfer_error(&self) -> Option<TransferError> {
+        if self.scheduled == 0 {
+            return None;
+        }
+        let td = &self.tds[self.head];
+        td.clean_invalidate_dcache(self.cache_policy);
+        TransferError::from_status(td.status())
+    }
+
+    /// Count the errors of the oldest scheduled transfer, once
+    pub fn count_errors(&mut self) {
+        if self.error_counted {
+            return;
+        }
+        if let Some(error) = self.transfer_error() {
+            self.error_counts.count(error);
+            self.error_counted = true;
+        }
+    }
+
+    /// Returns the errors of all failed transfers
+    pub fn error_counts(&self) -> ErrorCounts {
+        self.error_counts
+    }
+
     /// Initialize the endpoint, should be called soon after it's assigned,
     /// or after transitioning out of configuration (reset the endpoint).
+    ///
+    /// This forgets all scheduled transfers, so the endpoint must not be primed.
     pub fn initialize(&mut self, usb: &ral::AnyUsbInstance) {
+        self.reset_transfers();
+        self.terminate();
+        self.priming = false;
+        self.prime_frindex = None;
+        self.isochronous_error = None;
         if self.address.index() != 0 {
             let endptctrl = endpoint_control::register(usb, self.address.index());
             match self.address.direction() {
@@ -153,28 +564,114 @@ impl Endpoint {
         }
     }
 
-    /// Read data from the endpoint into `buffer`
+    /// Read data from the oldest scheduled transfer into `buffer`
     ///
     /// Returns the number of bytes read into `buffer`, which is constrained by the
-    /// max packet length, and the number of bytes received in the last transfer.
+    /// transfer length, and the number of bytes received in the transfer. Returns
+    /// zero if there are no scheduled transfers.
     pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        if self.scheduled == 0 {
+            return 0;
+        }
         let size = self
-            .qh
-            .max_packet_len()
+            .transfer_len
             .min(buffer.len())
-            .min(self.td.bytes_transferred());
-        self.buffer.volatile_read(&mut buffer[..size])
+            .min(self.tds[self.head].bytes_transferred());
+        let td_buffer = self.td_buffer(self.head);
+        // The CPU may have speculatively loaded the buffer while the
+        // controller was receiving.
+        td_buffer.invalidate_dcache(size);
+        td_buffer.volatile_read(&mut buffer[..size])
+    }
+
+    /// Lend a transfer's buffer
+    ///
+    /// For an OUT endpoint, this lends the data received by the oldest scheduled
+    /// transfer. The transfer stays scheduled until you [`release()`](Endpoint::release)
+    /// it, so the controller doesn't receive into the lent buffer. Returns `None`
+    /// if the transfer isn't complete.
+    ///
+    /// For an IN endpoint, this lends the next transfer's buffer, sized to the
+    /// transfer length. Returns `None` if the endpoint is full. Schedule the
+    /// transfer with [`commit()`](Endpoint::commit).
+    ///
+    /// Returns `None` if the endpoint already lent a buffer.
+    pub fn lend(&mut self) -> Option<Buffer> {
+        if self.lent.is_some() {
+            return None;
+        }
+        let (index, size) = match self.address.direction() {
+            UsbDirection::Out => {
+                if !self.is_complete() {
+                    return None;
+                }
+                let size = self
+                    .transfer_len
+                    .min(self.tds[self.head].bytes_transferred());
+                (self.head, size)
+            }
+            UsbDirection::In => {
+                if self.is_full() {
+                    return None;
+                }
+                (self.tail(), self.transfer_len)
+            }
+        };
+        let td_buffer = self.td_buffer(index);
+        if self.address.direction() == UsbDirection::Out {
+            td_buffer.invalidate_dcache(size);
+        }
+        self.lent = Some(index);
+        // Safety: the endpoint doesn't touch the lent TD buffer until it's
+        // released.
+        Some(unsafe { td_buffer.subrange(0, size) })
+    }
+
+    /// Indicates if the endpoint lent a buffer
+    pub fn is_lent(&self) -> bool {
+        self.lent.is_some()
+    }
+
+    /// Release the lent buffer
+    ///
+    /// For an OUT endpoint, this retires the lent transfer. For an IN endpoint,
+    /// this drops the transfer without sending it. Returns `false` if no buffer
+    /// is lent.
+    pub fn release(&mut self) -> bool {
+        if self.lent.take().is_none() {
+            return false;
+        }
+        match self.address.direction() {
+            UsbDirection::Out => self.retire(),
+            UsbDirection::In => true,
+        }
+    }
+
+    /// Schedule the lent IN transfer, sending `size` bytes of the lent buffer
+    ///
+    /// Returns the number of bytes scheduled, which is constrained by the
+    /// transfer length. Returns `None` if this isn't an IN endpoint, or if no
+    /// buffer is lent.
+    pub fn commit(&mut self, usb: &ral::AnyUsbInstance, size: usize) -> Option<usize> {
+        if self.address.direction() != UsbDirection::In {
+            return None;
+        }
+        self.lent.take()?;
+        let size = self.transfer_len.min(size);
+        self.schedule_transfer(usb, size);
+        Some(size)
     }
 
-    /// Write `buffer` to the endpoint buffer
+    /// Write `buffer` to the endpoint buffer of the next transfer
     ///
     /// Returns the number of bytes written from `buffer`, which is constrained
-    /// by the max packet length.
+    /// by the transfer length. Caller should make sure that the endpoint isn't
+    /// full before writing. [`schedule_transfer()`](Endpoint::schedule_transfer)
+    /// cleans the written data from DCache.
     pub fn write(&mut self, buffer: &[u8]) -> usize {
-        let size = self.qh.max_packet_len().min(buffer.len());
-        let written = self.buffer.volatile_write(&buffer[..size]);
-        self.buffer.clean_invalidate_dcache(size);
-        written
+        let size = self.transfer_len.min(buffer.len());
+        let mut td_buffer = self.td_buffer(self.tail());
+        td_buffer.volatile_write(&buffer[..size])
     }
 
     /// Clear the complete bit for this endpoint
@@ -191,18 +688,137 @@ impl Endpoint {
 
     /// Schedule a transfer of `size` bytes from the endpoint buffer
     ///
-    /// Caller should check to see if there is an active transfer, or if the previous
+    /// The transfer uses the next TD in the ring. If other transfers are
+    /// scheduled, the TD is added to the end of the primed list.
+    ///
+    /// For an IN endpoint, this cleans the transfer's data from DCache. For
+    /// an OUT endpoint, this invalidates the transfer's buffer, so that no
+    /// dirty cache line can be evicted over received data.
+    ///
+    /// Caller should check to see if the endpoint is full, or if the previous
     /// transfer resulted in an error or halt.
     pub fn schedule_transfer(&mut self, usb: &ral::AnyUsbInstance, size: usize) {
-        self.td.set_terminate();
-        self.td.set_buffer(self.buffer.as_ptr_mut(), size);
-        self.td.set_interrupt_on_complete(true);
-        self.td.set_active();
-        self.td.clean_invalidate_dcache();
+        debug_assert!(!self.is_full(), "No free TD for EP{}", self.address.index());
+        self.confirm_prime(usb);
+
+        // An isochronous IN transfer sends one or more packets per microframe.
+        let mult = if self.is_isochronous() && self.address.direction() == UsbDirection::In {
+            size.div_ceil(self.qh.max_packet_len().max(1)).max(1)
+        } else {
+            0
+        };
+
+        let tail = self.tail();
+        let mut td_buffer = self.td_buffer(tail);
+        match self.address.direction() {
+            UsbDirection::In => td_buffer.clean_dcache(size),
+            UsbDirection::Out => td_buffer.invalidate_dcache(size),
+        }
+
+        let td = &mut self.tds[tail];
+        td.set_terminate();
+        td.set_buffer(td_buffer.as_ptr_mut(), size);
+        td.set_mult_override(mult);
+        td.set_interrupt_on_complete(true);
+        td.set_active();
+        td.clean_invalidate_dcache(self.cache_policy);
+
+        let primed = self.scheduled != 0 && self.link(usb, tail);
+        self.scheduled += 1;
 
-        self.qh.overlay_mut().set_next(self.td);
+        if primed || self.deferred_prime.is_some() {
+            return;
+        }
+        if self.is_isochronous() {
+            self.defer_prime(usb, tail);
+        } else {
+            self.prime(usb, tail);
+        }
+    }
+
+    /// Prime the TD at `index` once the next (micro)frame starts
+    ///
+    /// A prime can finish at any point in a (micro)frame, so the first transfer
+    /// might move in the current (micro)frame, or in the next one. Waiting for a
+    /// boundary gives the controller most of a (micro)frame to finish the prime,
+    /// so the first transfer moves in a known (micro)frame. This enables SOF
+    /// interrupts, so that `poll()` runs at the boundary; see [`prime_deferred()`](Endpoint::prime_deferred).
+    fn defer_prime(&mut self, usb: &ral::AnyUsbInstance, index: usize) {
+        let frindex = ral::read_reg!(ral::usb, usb, FRINDEX);
+        self.deferred_prime = Some((index, frindex));
+        self.prime_frindex = None;
+        ral::modify_reg!(ral::usb, usb, USBINTR, SRE: 1);
+    }
+
+    /// Prime a deferred isochronous transfer, if a (micro)frame started since
+    /// it was scheduled
+    ///
+    /// Returns `true` if the prime is still deferred.
+    pub fn prime_deferred(&mut self, usb: &ral::AnyUsbInstance) -> bool {
+        let Some((index, scheduled)) = self.deferred_prime else {
+            return false;
+        };
+        let frindex = ral::read_reg!(ral::usb, usb, FRINDEX);
+        if frindex == scheduled {
+            return true;
+        }
+        self.deferred_prime = None;
+        self.prime_frindex = Some(frindex);
+        self.prime(usb, index);
+        false
+    }
+
+    /// Indicates if an isochronous prime waits for the next (micro)frame
+    pub fn is_prime_deferred(&self) -> bool {
+        self.deferred_prime.is_some()
+    }
+
+    /// Returns the FRINDEX value just after the (micro)frame boundary that
+    /// primed this isochronous endpoint
+    ///
+    /// The first transfer moves in the (micro)frame after this one. Returns
+    /// `None` if a prime is deferred, or if the endpoint wasn't primed at a
+    /// boundary.
+    pub fn prime_frindex(&self) -> Option<u32> {
+        self.prime_frindex
+    }
+
+    /// Add the TD at `index` to the end of this endpoint's primed list
+    ///
+    /// Implements the "add dTD to a primed list" algorithm from the reference
+    /// manual. Returns `true` if the controller will process the TD. Returns
+    /// `false` if the controller already finished the list, and the TD needs
+    /// to be primed.
+    fn link(&mut self, usb: &ral::AnyUsbInstance, index: usize) -> bool {
+        let previous = (index + self.tds.len() - 1) % self.tds.len();
+        let next: *const Td = &self.tds[index];
+        self.tds[previous].set_next(next);
+        self.tds[previous].clean_invalidate_dcache(self.cache_policy);
+
+        let bit = self.register_bit();
+        if ral::read_reg!(ral::usb, usb, ENDPTPRIME) & bit != 0 {
+            return true;
+        }
+
+        let active = loop {
+            ral::modify_reg!(ral::usb, usb, USBCMD, ATDTW: 1);
+            let active = ral::read_reg!(ral::usb, usb, ENDPTSTAT) & bit != 0;
+            if ral::read_reg!(ral::usb, usb, USBCMD, ATDTW == 1) {
+                break active;
+            }
+        };
+        ral::modify_reg!(ral::usb, usb, USBCMD, ATDTW: 0);
+        active
+    }
+
+    /// Point the QH at the TD at `index`, and prime the endpoint
+    ///
+    /// Doesn't wait for the controller to finish priming. See
+    /// [`confirm_prime()`](Endpoint::confirm_prime).
+    fn prime(&mut self, usb: &ral::AnyUsbInstance, index: usize) {
+        self.qh.overlay_mut().set_next(&self.tds[index]);
         self.qh.overlay_mut().clear_status();
-        self.qh.clean_invalidate_dcache();
+        self.qh.clean_invalidate_dcache(self.cache_policy);
 
         match self.address.direction() {
             UsbDirection::In => {
@@ -212,7 +828,34 @@ impl Endpoint {
                 ral::write_reg!(ral::usb, usb, ENDPTPRIME, PERB: 1 << self.address.index())
             }
         }
-        while ral::read_reg!(ral::usb, usb, ENDPTPRIME) != 0 {}
+        self.priming = true;
+    }
+
+    /// Flush all primed transfers from this endpoint
+    ///
+    /// Follows the reference manual's flush procedure, repeating the
+    /// flush until the endpoint is no longer primed, or priming. Afterwards,
+    /// the controller isn't processing any of this endpoint's TDs.
+    pub fn flush(&mut self, usb: &ral::AnyUsbInstance) {
+        let bit = self.register_bit();
+        loop {
+            ral::flush(usb, bit);
+            if !self.is_primed(usb) {
+                break;
+            }
+        }
+        self.priming = false;
+    }
+
+    /// Schedule receive transfers on every free TD
+    ///
+    /// Each transfer receives up to the transfer length, finishing early
+    /// if the host sends a short packet. This is only meaningful for an
+    /// OUT endpoint.
+    pub fn schedule_receives(&mut self, usb: &ral::AnyUsbInstance) {
+        while !self.is_full() {
+            self.schedule_transfer(usb, self.transfer_len);
+        }
     }
 
     /// Stall or unstall the endpoint
@@ -229,6 +872,104 @@ impl Endpoint {
         }
     }
 
+    /// Reset the data toggle, so that the next transaction uses DATA0
+    ///
+    /// Does nothing for a control endpoint.
+    pub fn reset_data_toggle(&mut self, usb: &ral::AnyUsbInstance) {
+        if self.address.index() == 0 {
+            return;
+        }
+        let endptctrl = endpoint_control::register(usb, self.address.index());
+        match self.address.direction() {
+            UsbDirection::In => {
+                ral::modify_reg!(endpoint_control, &endptctrl, ENDPTCTRL, TXR: 1)
+            }
+            UsbDirection::Out => {
+                ral::modify_reg!(endpoint_control, &endptctrl, ENDPTCTRL, RXR: 1)
+            }
+        }
+    }
+
+    /// Recover from a failed transfer
+    ///
+    /// Flushes the endpoint, drops all scheduled transfers, and clears the status
+    /// of every TD and the QH overlay. A lent transfer stays lent. If `reset_data_toggle`
+    /// is set, the next transaction uses DATA0. Finally, an enabled OUT endpoint
+    /// schedules receives on its free TDs. An IN endpoint's dropped transfers are
+    /// not sent again.
+    pub fn recover(&mut self, usb: &ral::AnyUsbInstance, reset_data_toggle: bool) {
+        self.drop_transfers(usb);
+        if reset_data_toggle {
+            self.reset_data_toggle(usb);
+        }
+        if self.address.direction() == UsbDirection::Out && self.is_enabled(usb) {
+            self.schedule_receives(usb);
+        }
+    }
+
+    /// Cancel all scheduled transfers
+    ///
+    /// Flushes the endpoint, and drops all scheduled transfers, including transfers
+    /// that completed but aren't retired. Returns the number of bytes that the dropped
+    /// transfers already moved, counting the progress of the transfer that the controller
+    /// was processing. A lent transfer stays lent, and isn't counted. Finally, an enabled
+    /// OUT endpoint schedules receives on its free TDs.
+    pub fn cancel(&mut self, usb: &ral::AnyUsbInstance) -> usize {
+        if self.is_primed(usb) {
+            self.flush(usb);
+        }
+        let moved = self.moved_len();
+        self.drop_transfers(usb);
+        if self.address.direction() == UsbDirection::Out && self.is_enabled(usb) {
+            self.schedule_receives(usb);
+        }
+        moved
+    }
+
+    /// Returns the number of bytes moved by the scheduled transfers
+    ///
+    /// Skips a lent OUT transfer. Only call this when the controller isn't
+    /// processing the endpoint's TDs.
+    fn moved_len(&self) -> usize {
+        self.qh.clean_invalidate_dcache(self.cache_policy);
+        let current = self.qh.current_td();
+        let lent = (self.lent.is_some() && self.address.direction() == UsbDirection::Out) as usize;
+        (lent..self.scheduled)
+            .map(|offset| &self.tds[(self.head + offset) % self.tds.len()])
+            .map(|td| {
+                td.clean_invalidate_dcache(self.cache_policy);
+                if !td.status().contains(Status::ACTIVE) {
+                    td.bytes_transferred()
+                } else if current == td as *const Td as u32 {
+                    // The controller doesn't write the overlay back into an active TD.
+                    td.size().saturating_sub(self.qh.overlay().remaining())
+                } else {
+                    0
+                }
+            })
+            .sum()
+    }
+
+    /// Flush the endpoint, then drop all scheduled transfers and clear their status
+    ///
+    /// A lent transfer stays lent.
+    fn drop_transfers(&mut self, usb: &ral::AnyUsbInstance) {
+        if self.is_primed(usb) {
+            self.flush(usb);
+        }
+        self.priming = false;
+
+        for td in self.tds.iter_mut() {
+            td.clear_status();
+            td.set_terminate();
+            td.clean_invalidate_dcache(self.cache_policy);
+        }
+        self.qh.overlay_mut().clear_status();
+        self.terminate();
+        self.reset_transfers();
+        self.clear_complete(usb);
+    }
+
     /// Indicates if the endpoint is stalled
     pub fn is_stalled(&self, usb: &ral::AnyUsbInstance) -> bool {
         let endptctrl = endpoint_control::register(usb, self.address.index());
@@ -294,3 +1035,359 @@ fn into_raw_endpoint_type(ep_type: EndpointType) -> u32 {
     // and it's compatible with ENDPTCTRL's enumerated values.
     (ep_type.to_bm_attributes() & 0b11u8).into()
 }
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+
+    use super::{Endpoint, ErrorCounts, IsochronousError, TransferError};
+    use crate::{
+        buffer::Allocator,
+        cache::CachePolicy,
+        qh::Qh,
+        ral,
+        td::{Status, Td},
+    };
+    use std::boxed::Box;
+    use usb_device::{
+        UsbError,
+        endpoint::{
+            EndpointAddress, EndpointType, IsochronousSynchronizationType, IsochronousUsageType,
+        },
+    };
+
+    /// Create a bulk endpoint with two TDs, each holding 64 bytes.
+    fn endpoint(address: u8) -> Endpoint {
+        endpoint_of(address, EndpointType::Bulk)
+    }
+
+    /// Create an endpoint with two TDs, each holding 64 bytes.
+    fn endpoint_of(address: u8, kind: EndpointType) -> Endpoint {
+        let qh = Box::leak(Box::new(Qh::new()));
+        let tds = Box::leak(Box::new([Td::new(), Td::new()]));
+        let memory: &'static mut [u8] = Box::leak(Box::new([0; 256]));
+        let buffer = Allocator::new(memory, CachePolicy::Skip)
+            .allocate(128)
+            .unwrap();
+        Endpoint::new(
+            EndpointAddress::from(address),
+            qh,
+            tds,
+            buffer,
+            kind,
+            CachePolicy::Skip,
+        )
+    }
+
+    /// Simulated USB registers.
+    fn usb() -> ral::AnyUsbInstance {
+        ral::erase_instances(ral::sim::Registers::leak().instances()).usb
+    }
+
+    /// Simulate a receive of `received` bytes into the next TD.
+    fn receive(ep: &mut Endpoint, received: usize) {
+        let tail = ep.tail();
+        let mut td_buffer = ep.td_buffer(tail);
+        ep.tds[tail].set_buffer(td_buffer.as_ptr_mut(), ep.transfer_len());
+        ep.tds[tail].simulate_completion(received);
+        ep.scheduled += 1;
+    }
+
+    #[test]
+    fn lend_and_release() {
+        let mut ep = endpoint(0x01);
+        assert!(ep.lend().is_none());
+        assert!(!ep.release());
+
+        ep.head = 1;
+        receive(&mut ep, 10);
+        let lent = ep.lend().unwrap();
+        assert_eq!(lent.len(), 10);
+        assert_eq!(lent.as_ptr(), ep.td_buffer(1).as_ptr());
+        assert!(ep.is_lent());
+        assert!(ep.lend().is_none());
+
+        // A reset keeps the lent transfer.
+        ep.reset_transfers();
+        assert_eq!((ep.head, ep.scheduled), (1, 1));
+        assert!(!ep.is_full());
+
+        assert!(ep.release());
+        assert!(!ep.is_lent());
+        assert_eq!((ep.head, ep.scheduled), (0, 0));
+        assert!(!ep.release());
+
+        ep.head = 1;
+        ep.reset_transfers();
+        assert_eq!((ep.head, ep.scheduled), (0, 0));
+    }
+
+    #[test]
+    fn confirm_prime() {
+        let usb = usb();
+        let mut ep = endpoint(0x81);
+        let bit = 1 << 17;
+        assert!(ep.confirm_prime(&usb));
+
+        // Priming doesn't wait for the controller.
+        ep.schedule_transfer(&usb, 8);
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), bit);
+        assert!(ep.is_primed(&usb));
+        assert!(!ep.confirm_prime(&usb));
+
+        // The controller primed the endpoint.
+        ral::write_reg!(ral::usb, usb, ENDPTPRIME, 0);
+        ral::write_reg!(ral::usb, usb, ENDPTSTAT, bit);
+        assert!(ep.confirm_prime(&usb));
+        assert!(ep.confirm_prime(&usb));
+    }
+
+    #[test]
+    fn confirm_prime_races() {
+        // (address, transfers scheduled, transfers completed, confirmed)
+        let cases = [
+            // The transfer completed before the driver observed ENDPTSTAT.
+            (0x81, 1, 1, true),
+            // The first transfer completed, but the prime of the second
+            // transfer failed.
+            (0x81, 2, 1, false),
+            // A setup packet cancelled the prime.
+            (0x80, 1, 0, true),
+        ];
+        for (address, scheduled, completed, confirmed) in cases {
+            let usb = usb();
+            let mut ep = endpoint(address);
+            for _ in 0..scheduled {
+                ep.schedule_transfer(&usb, 8);
+            }
+            ral::write_reg!(ral::usb, usb, ENDPTPRIME, 0);
+            for td in &mut ep.tds[..completed] {
+                td.simulate_completion(8);
+            }
+
+            let case = (address, scheduled, completed);
+            assert_eq!(ep.confirm_prime(&usb), confirmed, "{case:?}");
+            assert_eq!(ep.is_complete(), completed > 0, "{case:?}");
+            if confirmed {
+                assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 0, "{case:?}");
+            } else {
+                // Primes again, starting at the oldest incomplete transfer.
+                assert_eq!(
+                    ral::read_reg!(ral::usb, usb, ENDPTPRIME),
+                    1 << 17,
+                    "{case:?}"
+                );
+                let next: *const Td = &ep.tds[completed];
+                assert_eq!(ep.qh.overlay_mut().next(), next as u32, "{case:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn transfer_errors() {
+        let usb = usb();
+        let mut ep = endpoint(0x81);
+        assert_eq!(ep.transfer_error(), None);
+        assert_eq!(ep.check_errors(), Ok(()));
+
+        ep.schedule_transfer(&usb, 8);
+        ep.tds[0].simulate_status(Status::TRANSACTION_ERROR | Status::HALTED);
+        let error = TransferError {
+            transaction: true,
+            data_buffer: false,
+            halted: true,
+        };
+        assert_eq!(ep.transfer_error(), Some(error));
+        assert_eq!(ep.check_errors(), Err(UsbError::InvalidState));
+        assert_eq!(ep.check_errors(), Err(UsbError::InvalidState));
+        ep.count_errors();
+
+        // Each failed transfer counts once.
+        let counts = ErrorCounts {
+            transaction: 1,
+            data_buffer: 0,
+            halted: 1,
+        };
+        assert_eq!(ep.error_counts(), counts);
+    }
+
+    #[test]
+    fn isochronous_transfer_errors() {
+        let usb = usb();
+        let mut ep = endpoint_of(
+            0x81,
+            EndpointType::Isochronous {
+                synchronization: IsochronousSynchronizationType::NoSynchronization,
+                usage: IsochronousUsageType::Data,
+            },
+        );
+        ep.schedule_transfer(&usb, 8);
+        ep.schedule_transfer(&usb, 8);
+        ep.tds[0].simulate_status(Status::DATA_BUFFER_ERROR);
+        ep.tds[1].simulate_status(Status::DATA_BUFFER_ERROR);
+
+        // Isochronous endpoints don't fail on transfer errors.
+        assert_eq!(ep.check_errors(), Ok(()));
+        assert!(ep.retire_failed());
+        assert_eq!(ep.transfer_error(), None);
+        assert_eq!(ep.error_counts().data_buffer, 2);
+        assert_eq!(
+            ep.take_isochronous_error(),
+            Some(IsochronousError::DataBuffer)
+        );
+    }
+
+    #[test]
+    fn isochronous_deferred_prime() {
+        let usb = usb();
+        let mut ep = endpoint_of(
+            0x81,
+            EndpointType::Isochronous {
+                synchronization: IsochronousSynchronizationType::NoSynchronization,
+                usage: IsochronousUsageType::Data,
+            },
+        );
+        ral::write_reg!(ral::usb, usb, FRINDEX, 0x40);
+        ep.schedule_transfer(&usb, 8);
+        ep.schedule_transfer(&usb, 8);
+        assert!(ep.is_prime_deferred());
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 0);
+        assert_eq!(ral::read_reg!(ral::usb, usb, USBINTR, SRE), 1);
+
+        // Same (micro)frame.
+        assert!(ep.prime_deferred(&usb));
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 0);
+        assert_eq!(ep.prime_frindex(), None);
+
+        // The next (micro)frame primes the oldest transfer.
+        ral::write_reg!(ral::usb, usb, FRINDEX, 0x41);
+        assert!(!ep.prime_deferred(&usb));
+        assert!(!ep.is_prime_deferred());
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 1 << 17);
+        assert_eq!(ep.prime_frindex(), Some(0x41));
+        assert_eq!(ep.tds[0].next(), &ep.tds[1] as *const _ as u32);
+        assert!(!ep.prime_deferred(&usb));
+
+        // Dropping the transfers drops the deferred prime.
+        ral::write_reg!(ral::usb, usb, ENDPTPRIME, 0);
+        ep.reset_transfers();
+        ep.schedule_transfer(&usb, 8);
+        assert!(ep.is_prime_deferred());
+        ep.reset_transfers();
+        assert!(!ep.is_prime_deferred());
+    }
+
+    #[test]
+    fn recover_in() {
+        let usb = usb();
+        let mut ep = endpoint(0x81);
+        ep.schedule_transfer(&usb, 8);
+        ep.schedule_transfer(&usb, 8);
+        ral::write_reg!(ral::usb, usb, ENDPTPRIME, 0);
+        ep.tds[0].simulate_status(Status::TRANSACTION_ERROR);
+        assert_eq!(ep.check_errors(), Err(UsbError::InvalidState));
+
+        ep.recover(&usb, true);
+        assert_eq!(ep.check_errors(), Ok(()));
+        assert_eq!((ep.head, ep.scheduled), (0, 0));
+        assert!(ep.tds.iter().all(|td| td.status().is_empty()));
+        assert!(ep.qh.overlay_mut().status().is_empty());
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 0);
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTCTRL[0], TXR), 1);
+        assert_eq!(ep.error_counts().transaction, 1);
+
+        // The endpoint accepts transfers again.
+        ep.schedule_transfer(&usb, 8);
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 1 << 17);
+    }
+
+    #[test]
+    fn recover_out() {
+        let usb = usb();
+        let mut ep = endpoint(0x01);
+        ep.enable(&usb);
+        ep.schedule_receives(&usb);
+        ral::write_reg!(ral::usb, usb, ENDPTPRIME, 0);
+        ral::modify_reg!(ral::usb, usb, ENDPTCTRL[0], RXR: 0);
+        ep.tds[0].simulate_status(Status::DATA_BUFFER_ERROR | Status::HALTED);
+
+        // Keeps the data toggle, and receives on every TD.
+        ep.recover(&usb, false);
+        assert_eq!(ep.check_errors(), Ok(()));
+        assert_eq!((ep.head, ep.scheduled), (0, 2));
+        assert!(
+            ep.tds
+                .iter()
+                .all(|td| td.status().bits() == Status::ACTIVE.bits())
+        );
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 1 << 1);
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTCTRL[0], RXR), 0);
+    }
+
+    #[test]
+    fn cancel_in() {
+        let usb = usb();
+        let mut ep = endpoint(0x81);
+        ep.schedule_transfer(&usb, 8);
+        ep.schedule_transfer(&usb, 16);
+        ral::write_reg!(ral::usb, usb, ENDPTPRIME, 0);
+        ep.tds[0].simulate_completion(8);
+        ep.qh.simulate_current(&ep.tds[1], 5);
+
+        // The first transfer completed, and the second sent part of its data.
+        assert_eq!(ep.cancel(&usb), 13);
+        assert_eq!((ep.head, ep.scheduled), (0, 0));
+        assert!(ep.tds.iter().all(|td| td.status().is_empty()));
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 0);
+        assert_eq!(ep.cancel(&usb), 0);
+
+        ep.schedule_transfer(&usb, 8);
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 1 << 17);
+    }
+
+    #[test]
+    fn cancel_out() {
+        let usb = usb();
+        let mut ep = endpoint(0x01);
+        ep.enable(&usb);
+        ep.schedule_receives(&usb);
+        ral::write_reg!(ral::usb, usb, ENDPTPRIME, 0);
+        ep.tds[0].simulate_completion(10);
+        ep.qh.simulate_current(&ep.tds[1], 3);
+        ep.lend().unwrap();
+
+        // The lent transfer isn't counted, and stays lent.
+        assert_eq!(ep.cancel(&usb), 3);
+        assert!(ep.is_lent());
+        assert_eq!((ep.head, ep.scheduled), (0, 2));
+        assert_eq!(ral::read_reg!(ral::usb, usb, ENDPTPRIME), 1 << 1);
+
+        assert!(ep.release());
+        assert_eq!((ep.head, ep.scheduled), (1, 1));
+    }
+
+    #[test]
+    fn lend_in() {
+        let mut ep = endpoint(0x81);
+        ep.head = 1;
+        ep.scheduled = 1;
+        let lent = ep.lend().unwrap();
+        assert_eq!(lent.len(), 64);
+        assert_eq!(lent.as_ptr(), ep.td_buffer(0).as_ptr());
+        assert!(ep.lend().is_none());
+
+        // A reset keeps the lent buffer as the next transfer.
+        ep.reset_transfers();
+        assert_eq!((ep.head, ep.scheduled), (0, 0));
+        assert_eq!(ep.tail(), 0);
+
+        // Releasing drops the transfer.
+        assert!(ep.release());
+        assert_eq!((ep.head, ep.scheduled), (0, 0));
+
+        // No buffer while every TD is scheduled.
+        ep.scheduled = 2;
+        assert!(ep.lend().is_none());
+        assert!(!ep.is_lent());
+    }
+}
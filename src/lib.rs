@@ -20,6 +20,17 @@
 //!
 //! Enable the `defmt` feature to activate internal logging using defmt.
 //!
+//! # Cache maintenance
+//!
+//! By default, the driver maintains the D-cache for all endpoint memory and
+//! endpoint state. Enable the `skip-dcache-maintenance` feature to skip this
+//! maintenance by default. See [`CachePolicy`] for more information.
+//!
+//! # Embassy
+//!
+//! Enable the `embassy` feature to use the driver with `embassy-usb`. See the
+//! [`embassy`] module for more information.
+//!
 //! # Example
 //!
 //! ```no_run
@@ -59,8 +70,14 @@ mod state;
 mod td;
 mod vcell;
 
-pub use buffer::EndpointMemory;
-pub use bus::{BusAdapter, Speed};
+pub use buffer::{EndpointMemory, MAX_REGIONS, Placement};
+pub use bus::{
+    BusAdapter, BusSpeed, EndpointStatus, EndpointUsage, ErrorCounts, Frame, IsochronousError,
+    MemoryUsage, NakEvents, ReadGuard, Speed, TransferError, VbusEvent, WriteGuard,
+};
+pub use cache::CachePolicy;
+#[cfg(feature = "embassy")]
+pub mod embassy;
 pub mod gpt;
 pub use state::{EndpointState, MAX_ENDPOINTS};
 
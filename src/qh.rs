@@ -3,13 +3,12 @@
 #![allow(non_snake_case, non_upper_case_globals)]
 
 use crate::ral;
-use crate::{td::Td, vcell::VCell};
+use crate::{cache::CachePolicy, td::Td, vcell::VCell};
 
 #[repr(C, align(64))]
 pub struct Qh {
     CAPABILITIES: VCell<u32>,
-    // No need to see this...
-    _current_td_pointer: u32,
+    CURRENT_TD_POINTER: VCell<u32>,
     overlay: Td,
     setup: VCell<u64>,
 }
@@ -19,7 +18,7 @@ impl Qh {
     pub const fn new() -> Self {
         Qh {
             CAPABILITIES: VCell::new(0),
-            _current_td_pointer: 0,
+            CURRENT_TD_POINTER: VCell::new(0),
             overlay: Td::new(),
             setup: VCell::new(0),
         }
@@ -34,6 +33,26 @@ impl Qh {
         self.setup.read()
     }
 
+    /// Returns the address of the TD that the controller is processing
+    ///
+    /// The controller updates this when it loads a TD into the overlay.
+    pub fn current_td(&self) -> u32 {
+        self.CURRENT_TD_POINTER.read()
+    }
+
+    /// Simulate the controller processing `td`, after it moved `moved` bytes
+    #[cfg(test)]
+    pub fn simulate_current(&mut self, td: &Td, moved: usize) {
+        self.CURRENT_TD_POINTER.write(td as *const Td as u32);
+        self.overlay
+            .set_buffer(core::ptr::null_mut(), td.size() - moved);
+    }
+
+    /// Returns the TD overlay
+    pub fn overlay(&self) -> &Td {
+        &self.overlay
+    }
+
     /// Returns the next TD overlay
     pub fn overlay_mut(&mut self) -> &mut Td {
         &mut self.overlay
@@ -51,6 +70,14 @@ impl Qh {
         ral::read_reg!(crate::qh, self, CAPABILITIES, MAXIMUM_PACKET_LENGTH) as usize
     }
 
+    /// Set the number of packets per transaction
+    ///
+    /// Only meaningful for isochronous endpoints. Use zero for all other
+    /// endpoints. Clamps `mult` to 3.
+    pub fn set_mult(&mut self, mult: usize) {
+        ral::modify_reg!(crate::qh, self, CAPABILITIES, MULT: mult.min(3) as u32);
+    }
+
     /// Enable (true) or disable (false) zero length termination
     pub fn set_zero_length_termination(&mut self, zlt: bool) {
         // 0 == Enable zero length packet when transfer is equal to multiple of max packet length
@@ -63,16 +90,23 @@ impl Qh {
         ral::modify_reg!(crate::qh, self, CAPABILITIES, IOS: ios as u32);
     }
 
-    /// Clean and invalidate this QH from DCache
-    pub fn clean_invalidate_dcache(&self) {
-        crate::cache::clean_invalidate_dcache_by_address(
-            &self as *const _ as usize,
+    /// Clean and invalidate this QH from DCache, if `policy` allows it
+    pub fn clean_invalidate_dcache(&self, policy: CachePolicy) {
+        policy.clean_invalidate_dcache_by_address(
+            self as *const _ as usize,
             core::mem::size_of_val(self),
         );
     }
 }
 
 mod CAPABILITIES {
+    pub mod MULT {
+        pub const offset: u32 = 30;
+        pub const mask: u32 = 0b11 << offset;
+        pub mod RW {}
+        pub mod R {}
+        pub mod W {}
+    }
     pub mod ZLT {
         pub const offset: u32 = 29;
         pub const mask: u32 = 1 << offset;
@@ -101,6 +135,22 @@ const _: [(); 1] = [(); (core::mem::size_of::<Qh>() <= 64) as usize];
 #[cfg(test)]
 mod test {
     use super::Qh;
+    use crate::cache::{CachePolicy, Operation, recorder};
+
+    #[test]
+    fn clean_invalidate_dcache_address() {
+        let qh = Qh::new();
+        recorder::take();
+        qh.clean_invalidate_dcache(CachePolicy::Maintain);
+        assert_eq!(
+            recorder::take(),
+            [(
+                Operation::CleanInvalidate,
+                &qh as *const Qh as usize,
+                core::mem::size_of::<Qh>()
+            )]
+        );
+    }
 
     #[test]
     fn max_packet_len() {
@@ -117,6 +167,15 @@ mod test {
         assert_eq!(qh.CAPABILITIES.read(), 1 << 15);
     }
 
+    #[test]
+    fn mult() {
+        let mut qh = Qh::new();
+        qh.set_mult(2);
+        assert_eq!(qh.CAPABILITIES.read(), 2 << 30);
+        qh.set_mult(7);
+        assert_eq!(qh.CAPABILITIES.read(), 3 << 30);
+    }
+
     #[test]
     fn zlt() {
         let mut qh = Qh::new();
@@ -1,6 +1,6 @@
 //! Re-exports and helpers for imxrt-ral register access.
 
-pub use imxrt_ral::{modify_reg, read_reg, usb, usbphy, write_reg};
+pub use imxrt_ral::{RORegister, modify_reg, read_reg, usb, usbphy, write_reg};
 
 /// The "don't care" peripheral instance number.
 const ANY_INSTANCE: u8 = u8::MAX;
@@ -47,6 +47,18 @@ pub(crate) fn erase_instances<const N: u8>(instances: super::Instances<N>) -> Er
     }
 }
 
+/// Flush the endpoints selected by `mask`, and wait for the controller to finish
+///
+/// `mask` uses the ENDPTFLUSH bit layout. Afterwards, the controller may still
+/// report a selected endpoint as primed, if it primed the endpoint during the flush.
+pub fn flush(usb: &AnyUsbInstance, mask: u32) {
+    write_reg!(usb, usb, ENDPTFLUSH, mask);
+    while read_reg!(usb, usb, ENDPTFLUSH) & mask != 0 {
+        #[cfg(test)]
+        sim::finish_flush(usb);
+    }
+}
+
 /// The RAL API requires us to treat all endpoint control registers as unique.
 /// We can make it a little easier with this function, the `EndptCtrl` type,
 /// and the helper module.
@@ -73,3 +85,75 @@ pub mod endpoint_control {
         }
     }
 }
+
+/// Simulated register blocks for host tests.
+///
+/// The register blocks are plain memory. They don't behave like the
+/// USB controller, so tests must avoid driver paths that wait for the
+/// controller to change a register. The exception is a [`flush`](super::flush),
+/// which the simulation finishes.
+#[cfg(test)]
+pub(crate) mod sim {
+    extern crate std;
+
+    use core::mem::MaybeUninit;
+    use imxrt_ral::{usb, usbnc, usbphy};
+    use std::boxed::Box;
+
+    /// Zero-initialized USB, USBNC, and USBPHY register blocks.
+    pub struct Registers {
+        pub usb: usb::RegisterBlock,
+        pub usbnc: usbnc::RegisterBlock,
+        pub usbphy: usbphy::RegisterBlock,
+    }
+
+    impl Registers {
+        /// Allocate zeroed registers that live for the rest of the test.
+        pub fn leak() -> &'static Self {
+            // Safety: register blocks are collections of integer registers,
+            // so all zeros is a valid bit pattern.
+            Box::leak(Box::new(unsafe { MaybeUninit::zeroed().assume_init() }))
+        }
+
+        /// Produce instances that point at these register blocks.
+        pub fn instances(&'static self) -> crate::Instances<1> {
+            // Safety: registers are static. The test owns these registers.
+            unsafe {
+                crate::Instances {
+                    usb: imxrt_ral::Instance::new(&self.usb),
+                    usbnc: imxrt_ral::Instance::new(&self.usbnc),
+                    usbphy: imxrt_ral::Instance::new(&self.usbphy),
+                }
+            }
+        }
+    }
+
+    /// A zeroed read-only register, and a handle to change its value
+    pub fn ro_register() -> (
+        &'static imxrt_ral::RWRegister<u32>,
+        &'static imxrt_ral::RORegister<u32>,
+    ) {
+        // Safety: a register is an integer, so all zeros is a valid bit pattern.
+        let register: &'static imxrt_ral::RWRegister<u32> =
+            Box::leak(Box::new(unsafe { MaybeUninit::zeroed().assume_init() }));
+        // Safety: both register types are transparent wrappers around an
+        // UnsafeCell<u32>.
+        let read_only = unsafe {
+            &*(register as *const imxrt_ral::RWRegister<u32> as *const imxrt_ral::RORegister<u32>)
+        };
+        (register, read_only)
+    }
+
+    /// Finish a flush, like the controller would
+    ///
+    /// Clears the ENDPTFLUSH bits, and the flushed endpoints' ENDPTPRIME
+    /// and ENDPTSTAT bits. A prime that's still in progress finishes after
+    /// the flush, so it sets the endpoint's ENDPTSTAT bit.
+    pub fn finish_flush(usb: &super::AnyUsbInstance) {
+        let flushed = super::read_reg!(super::usb, usb, ENDPTFLUSH);
+        let late = super::read_reg!(super::usb, usb, ENDPTPRIME) & flushed;
+        super::modify_reg!(super::usb, usb, ENDPTPRIME, |prime| prime & !flushed);
+        super::modify_reg!(super::usb, usb, ENDPTSTAT, |stat| (stat & !flushed) | late);
+        super::write_reg!(super::usb, usb, ENDPTFLUSH, 0);
+    }
+}
@@ -6,7 +6,7 @@ use core::{
     sync::atomic::{AtomicU32, Ordering},
 };
 
-use crate::{buffer::Buffer, endpoint::Endpoint, qh::Qh, td::Td};
+use crate::{buffer::Buffer, cache::CachePolicy, endpoint::Endpoint, qh::Qh, td::Td};
 use usb_device::{
     UsbDirection,
     endpoint::{EndpointAddress, EndpointType},
@@ -14,14 +14,14 @@ use usb_device::{
 
 /// A list of transfer descriptors
 ///
-/// Supports 1 TD per QH (per endpoint direction)
+/// Supports `TD_COUNT` TDs per QH (per endpoint direction)
 #[repr(align(32))]
-struct TdList<const COUNT: usize>([UnsafeCell<Td>; COUNT]);
+struct TdList<const COUNT: usize, const TD_COUNT: usize>([[UnsafeCell<Td>; TD_COUNT]; COUNT]);
 
-impl<const COUNT: usize> TdList<COUNT> {
+impl<const COUNT: usize, const TD_COUNT: usize> TdList<COUNT, TD_COUNT> {
     const fn new() -> Self {
         const TD: UnsafeCell<Td> = UnsafeCell::new(Td::new());
-        Self([TD; COUNT])
+        Self([const { [TD; TD_COUNT] }; COUNT])
     }
 }
 
@@ -57,7 +57,7 @@ impl<const COUNT: usize> EpList<COUNT> {
 pub const MAX_ENDPOINTS: usize = 8 * 2;
 
 /// Produces an index into the EPs, QHs, and TDs collections
-fn index(ep_addr: EndpointAddress) -> usize {
+pub(crate) fn index(ep_addr: EndpointAddress) -> usize {
     (ep_addr.index() * 2) + (UsbDirection::In == ep_addr.direction()) as usize
 }
 
@@ -94,17 +94,52 @@ fn index(ep_addr: EndpointAddress) -> usize {
 /// ```
 ///
 /// Any endpoint state allocated beyond [`MAX_ENDPOINTS`] are wasted.
-pub struct EndpointState<const COUNT: usize = MAX_ENDPOINTS> {
+///
+/// # Transfer descriptors
+///
+/// By default, each endpoint has a single transfer descriptor (TD), so
+/// only one transfer can be in flight per endpoint. Use the const generic
+/// `TD_COUNT` to give each non-control endpoint a pool of TDs. The driver
+/// queues transfers on these TDs, and retires them in order, letting the
+/// controller move data back-to-back without waiting for software. Control
+/// endpoints always use a single TD.
+///
+/// ```
+/// use imxrt_usbd::{EndpointState, MAX_ENDPOINTS};
+///
+/// static EP_STATE: EndpointState<MAX_ENDPOINTS, 4> = EndpointState::new();
+/// ```
+///
+/// Each TD needs its own endpoint buffer, so an endpoint with `TD_COUNT`
+/// TDs consumes `TD_COUNT` times as much [`EndpointMemory`](crate::EndpointMemory).
+/// When `TD_COUNT` is larger than one, software modifies TDs that the controller
+/// might be processing. Place the endpoint state in non-cacheable memory, like
+/// DTCM, so that cache maintenance cannot race with the controller.
+///
+/// # Cache maintenance
+///
+/// If the endpoint state is non-cacheable, you can skip D-cache maintenance
+/// on QHs and TDs. See [`CachePolicy`](crate::CachePolicy) for more information.
+///
+/// ```
+/// use imxrt_usbd::{CachePolicy, EndpointState};
+///
+/// // TODO place in DTCM...
+/// static EP_STATE: EndpointState =
+///     EndpointState::max_endpoints().with_cache_policy(CachePolicy::Skip);
+/// ```
+pub struct EndpointState<const COUNT: usize = MAX_ENDPOINTS, const TD_COUNT: usize = 1> {
     qh_list: QhList<COUNT>,
-    td_list: TdList<COUNT>,
+    td_list: TdList<COUNT, TD_COUNT>,
     ep_list: EpList<COUNT>,
+    cache_policy: CachePolicy,
     /// Low 16 bits are used for tracking endpoint allocation.
     /// Bit 31 is set when the allocator is first taken. This
     /// bit is always dropped during u32 -> u16 conversions.
     alloc_mask: AtomicU32,
 }
 
-unsafe impl<const COUNT: usize> Sync for EndpointState<COUNT> {}
+unsafe impl<const COUNT: usize, const TD_COUNT: usize> Sync for EndpointState<COUNT, TD_COUNT> {}
 
 impl EndpointState<MAX_ENDPOINTS> {
     /// Allocate space for the maximum number of endpoints.
@@ -116,23 +151,39 @@ impl EndpointState<MAX_ENDPOINTS> {
     }
 }
 
-impl<const COUNT: usize> Default for EndpointState<COUNT> {
+impl<const COUNT: usize, const TD_COUNT: usize> Default for EndpointState<COUNT, TD_COUNT> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl<const COUNT: usize> EndpointState<COUNT> {
-    /// Allocate state for `COUNT` endpoints.
+impl<const COUNT: usize, const TD_COUNT: usize> EndpointState<COUNT, TD_COUNT> {
+    /// Allocate state for `COUNT` endpoints, each with `TD_COUNT` transfer descriptors.
+    ///
+    /// The state uses the default [`CachePolicy`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `TD_COUNT` is zero.
     pub const fn new() -> Self {
+        assert!(TD_COUNT > 0, "Each endpoint needs at least one TD");
         Self {
             qh_list: QhList::new(),
             td_list: TdList::new(),
             ep_list: EpList::new(),
+            cache_policy: CachePolicy::DEFAULT,
             alloc_mask: AtomicU32::new(0),
         }
     }
 
+    /// Set the D-cache maintenance policy for QHs and TDs.
+    pub const fn with_cache_policy(self, cache_policy: CachePolicy) -> Self {
+        Self {
+            cache_policy,
+            ..self
+        }
+    }
+
     /// Acquire the allocator.
     ///
     /// Returns `None` if the allocator was already taken.
@@ -141,18 +192,26 @@ impl<const COUNT: usize> EndpointState<COUNT> {
         let alloc_mask = self.alloc_mask.fetch_or(ALLOCATOR_TAKEN, Ordering::SeqCst);
         (alloc_mask & ALLOCATOR_TAKEN == 0).then(|| EndpointAllocator {
             qh_list: &self.qh_list.0[..self.qh_list.0.len().min(MAX_ENDPOINTS)],
-            td_list: &self.td_list.0[..self.td_list.0.len().min(MAX_ENDPOINTS)],
+            td_list: self.td_list.0[..self.td_list.0.len().min(MAX_ENDPOINTS)].as_flattened(),
+            td_count: TD_COUNT,
             ep_list: &self.ep_list.0[..self.ep_list.0.len().min(MAX_ENDPOINTS)],
+            cache_policy: self.cache_policy,
             alloc_mask: &self.alloc_mask,
+            allocated_ever: 0,
         })
     }
 }
 
 pub struct EndpointAllocator<'a> {
     qh_list: &'a [UnsafeCell<Qh>],
+    /// All TDs, `td_count` for each endpoint.
     td_list: &'a [UnsafeCell<Td>],
+    td_count: usize,
     ep_list: &'a [UnsafeCell<MaybeUninit<Endpoint>>],
+    cache_policy: CachePolicy,
     alloc_mask: &'a AtomicU32,
+    /// Every endpoint that was ever allocated.
+    allocated_ever: u16,
 }
 
 unsafe impl Send for EndpointAllocator<'_> {}
@@ -179,6 +238,25 @@ impl EndpointAllocator<'_> {
         self.qh_list.as_ptr().cast()
     }
 
+    /// Returns the number of endpoints that this state can allocate.
+    pub fn capacity(&self) -> usize {
+        self.qh_list.len()
+    }
+
+    /// Returns the smallest `COUNT` that holds every endpoint ever allocated.
+    pub fn required_count(&self) -> usize {
+        (u16::BITS - self.allocated_ever.leading_zeros()) as usize
+    }
+
+    /// Returns the number of transfer descriptors used by an endpoint of type `kind`.
+    pub fn transfer_descriptors(&self, kind: EndpointType) -> usize {
+        if kind == EndpointType::Control {
+            1
+        } else {
+            self.td_count
+        }
+    }
+
     /// Acquire the endpoint.
     ///
     /// Returns `None` if the endpoint isn't allocated.
@@ -262,12 +340,16 @@ impl EndpointAllocator<'_> {
         // If we pass this call, we're the only caller able to observe mutable
         // QHs, TDs, and EPs at index.
         self.try_mask_update(mask)?;
+        self.allocated_ever |= mask;
 
         // Safety: index in range. Atomic update on alloc_mask prevents races for
         // allocation, and ensures that we only release one &mut reference for each
         // component.
         let qh = unsafe { &mut *self.qh_list[index].get() };
-        let td = unsafe { &mut *self.td_list[index].get() };
+        let tds = &self.td_list[index * self.td_count..][..self.transfer_descriptors(kind)];
+        let tds = unsafe {
+            core::slice::from_raw_parts_mut(UnsafeCell::raw_get(tds.as_ptr()), tds.len())
+        };
         // We cannot access these two components after this call. The endpoint
         // takes mutable references, so it has exclusive ownership of both.
         // This module is designed to isolate this access so we can visually
@@ -276,10 +358,35 @@ impl EndpointAllocator<'_> {
         // EP is uninitialized.
         let ep = unsafe { &mut *self.ep_list[index].get() };
         // Nothing to drop here.
-        ep.write(Endpoint::new(addr, qh, td, buffer, kind));
+        ep.write(Endpoint::new(
+            addr,
+            qh,
+            tds,
+            buffer,
+            kind,
+            self.cache_policy,
+        ));
         // Safety: EP is initialized.
         Some(unsafe { ep.assume_init_mut() })
     }
+
+    /// Deallocate the endpoint for the specified address, returning its buffer.
+    ///
+    /// Returns `None` if the endpoint isn't allocated. Once deallocated, the
+    /// endpoint's QH and TDs can be allocated again. The controller must not
+    /// be using the endpoint.
+    pub fn deallocate_endpoint(&mut self, addr: EndpointAddress) -> Option<Buffer> {
+        let index = index(addr);
+        self.check_allocated(index)?;
+
+        // Safety: endpoint is allocated, checked above. The mutable receiver
+        // means that there's no other reference to the endpoint. We clear the
+        // allocation bit below, so we never read the endpoint again.
+        let ep = unsafe { (*self.ep_list[index].get()).assume_init_read() };
+        self.alloc_mask
+            .fetch_and(!(1u32 << index), Ordering::SeqCst);
+        Some(ep.into_buffer())
+    }
 }
 
 #[cfg(test)]
@@ -298,7 +405,7 @@ mod tests {
 
     #[test]
     fn allocate_endpoint() {
-        let mut buffer = [0; 128];
+        let mut buffer = [0; 256];
         let mut buffer_alloc = unsafe { buffer::Allocator::from_buffer(&mut buffer) };
         let ep_state = EndpointState::max_endpoints();
         let mut ep_alloc = ep_state.allocator().unwrap();
@@ -379,4 +486,146 @@ mod tests {
 
         assert_eq!(ep_alloc.endpoints_iter_mut().count(), 3);
     }
+
+    #[test]
+    fn deallocate_endpoint() {
+        let mut buffer = [0; 256];
+        let mut buffer_alloc = unsafe { buffer::Allocator::from_buffer(&mut buffer) };
+        let ep_state: EndpointState<4, 2> = EndpointState::new();
+        let mut ep_alloc = ep_state.allocator().unwrap();
+
+        let addr = EndpointAddress::from(1);
+        assert!(ep_alloc.deallocate_endpoint(addr).is_none());
+
+        ep_alloc
+            .allocate_endpoint(
+                addr,
+                buffer_alloc.allocate(2 * 32).unwrap(),
+                EndpointType::Bulk,
+            )
+            .unwrap();
+        let buffer = ep_alloc.deallocate_endpoint(addr).unwrap();
+        assert_eq!(buffer.len(), 2 * 32);
+        assert!(ep_alloc.endpoint(addr).is_none());
+        assert!(ep_alloc.deallocate_endpoint(addr).is_none());
+        assert!(buffer_alloc.free(buffer));
+        assert_eq!(ep_alloc.required_count(), 3);
+
+        // Reallocate with a different max packet size.
+        let ep = ep_alloc
+            .allocate_endpoint(
+                addr,
+                buffer_alloc.allocate(2 * 64).unwrap(),
+                EndpointType::Interrupt,
+            )
+            .unwrap();
+        assert_eq!(ep.max_packet_len(), 64);
+        assert_eq!(ep_alloc.endpoints_iter_mut().count(), 1);
+    }
+
+    #[test]
+    fn transfer_descriptor_pool() {
+        let mut buffer = [0; 128];
+        let mut buffer_alloc = unsafe { buffer::Allocator::from_buffer(&mut buffer) };
+        let ep_state: EndpointState<4, 3> = EndpointState::new();
+        let mut ep_alloc = ep_state.allocator().unwrap();
+        assert_eq!(ep_alloc.capacity(), 4);
+        assert_eq!(ep_alloc.required_count(), 0);
+
+        assert_eq!(ep_alloc.transfer_descriptors(EndpointType::Control), 1);
+        assert_eq!(ep_alloc.transfer_descriptors(EndpointType::Bulk), 3);
+        assert_eq!(ep_alloc.td_list.len(), 4 * 3);
+
+        let addr = EndpointAddress::from(0);
+        let ep = ep_alloc
+            .allocate_endpoint(
+                addr,
+                buffer_alloc.allocate(8).unwrap(),
+                EndpointType::Control,
+            )
+            .unwrap();
+        assert_eq!(ep.max_packet_len(), 8);
+
+        let addr = EndpointAddress::from(1);
+        let ep = ep_alloc
+            .allocate_endpoint(
+                addr,
+                buffer_alloc.allocate(3 * 16).unwrap(),
+                EndpointType::Bulk,
+            )
+            .unwrap();
+        assert_eq!(ep.max_packet_len(), 16);
+        assert!(!ep.is_full());
+        assert!(!ep.is_complete());
+        assert!(ep.check_errors().is_ok());
+    }
+
+    #[test]
+    fn multi_packet_transfers() {
+        let mut buffer = [0; 8192];
+        let mut buffer_alloc = unsafe { buffer::Allocator::from_buffer(&mut buffer) };
+        let ep_state: EndpointState<4, 2> = EndpointState::new();
+        let mut ep_alloc = ep_state.allocator().unwrap();
+
+        let ep = ep_alloc
+            .allocate_endpoint(
+                EndpointAddress::from(1),
+                buffer_alloc.allocate(2 * 2048).unwrap(),
+                EndpointType::Bulk,
+            )
+            .unwrap();
+        assert_eq!(ep.transfer_len(), 2048);
+        ep.set_max_packet_len(512);
+        assert_eq!(ep.max_packet_len(), 512);
+        assert_eq!(ep.transfer_len(), 2048);
+
+        let ep = ep_alloc
+            .allocate_endpoint(
+                EndpointAddress::from(0x81),
+                buffer_alloc.allocate(2 * 1000).unwrap(),
+                EndpointType::Bulk,
+            )
+            .unwrap();
+        ep.set_max_packet_len(64);
+        assert_eq!(ep.max_packet_len(), 64);
+        assert_eq!(ep.transfer_len(), 960);
+    }
+
+    #[test]
+    fn isochronous_transfers() {
+        use usb_device::endpoint::{IsochronousSynchronizationType, IsochronousUsageType};
+        const ISOCHRONOUS: EndpointType = EndpointType::Isochronous {
+            synchronization: IsochronousSynchronizationType::Asynchronous,
+            usage: IsochronousUsageType::Data,
+        };
+
+        let mut buffer = [0; 8192];
+        let mut buffer_alloc = unsafe { buffer::Allocator::from_buffer(&mut buffer) };
+        let ep_state: EndpointState<4, 2> = EndpointState::new();
+        let mut ep_alloc = ep_state.allocator().unwrap();
+
+        // Three 1024 byte packets per microframe.
+        let ep = ep_alloc
+            .allocate_endpoint(
+                EndpointAddress::from(0x81),
+                buffer_alloc.allocate(2 * 3072).unwrap(),
+                ISOCHRONOUS,
+            )
+            .unwrap();
+        ep.set_max_packet_len(1024 | (2 << 11));
+        assert_eq!(ep.max_packet_len(), 1024);
+        assert_eq!(ep.transfer_len(), 3072);
+
+        // One packet per frame.
+        let ep = ep_alloc
+            .allocate_endpoint(
+                EndpointAddress::from(1),
+                buffer_alloc.allocate(2 * 192).unwrap(),
+                ISOCHRONOUS,
+            )
+            .unwrap();
+        ep.set_max_packet_len(192);
+        assert_eq!(ep.max_packet_len(), 192);
+        assert_eq!(ep.transfer_len(), 192);
+    }
 }
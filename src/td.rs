@@ -5,7 +5,23 @@
 
 #![allow(non_snake_case, non_upper_case_globals)]
 
-use crate::{ral, vcell::VCell};
+use crate::{cache::CachePolicy, ral, vcell::VCell};
+
+/// The size of the memory page described by each buffer pointer.
+const PAGE_SIZE: usize = 4096;
+
+/// The maximum number of bytes that a single TD can transfer.
+///
+/// Five buffer pointers can describe this much memory only if the
+/// buffer starts on a page boundary.
+pub const MAX_TRANSFER_LEN: usize = 5 * PAGE_SIZE;
+
+/// The maximum number of bytes that a single TD can transfer from a
+/// buffer that may not start on a page boundary.
+pub const MAX_UNALIGNED_TRANSFER_LEN: usize = 4 * PAGE_SIZE;
+
+/// The buffer alignment required to reach [`MAX_TRANSFER_LEN`].
+pub const TRANSFER_ALIGNMENT: usize = PAGE_SIZE;
 
 #[repr(C)]
 pub struct Td {
@@ -42,7 +58,7 @@ impl Td {
         self.last_transfer_size = size;
 
         if size != 0 {
-            const PTR_ALIGNMENT: u32 = 4096;
+            const PTR_ALIGNMENT: u32 = PAGE_SIZE as u32;
             const PTR_MASK: u32 = !(PTR_ALIGNMENT - 1);
 
             self.BUFFER_POINTERS[0].write(ptr as u32);
@@ -61,8 +77,36 @@ impl Td {
 
     /// Returns the number of bytes transferred in the previous transfer
     pub fn bytes_transferred(&self) -> usize {
-        let total_bytes = ral::read_reg!(crate::td, self, TOKEN, TOTAL_BYTES) as usize;
-        self.last_transfer_size - total_bytes
+        self.last_transfer_size - self.remaining()
+    }
+
+    /// Returns the size of the previous transfer
+    pub fn size(&self) -> usize {
+        self.last_transfer_size
+    }
+
+    /// Returns the number of bytes that the transfer has yet to move
+    pub fn remaining(&self) -> usize {
+        ral::read_reg!(crate::td, self, TOKEN, TOTAL_BYTES) as usize
+    }
+
+    /// Returns the raw next TD pointer
+    #[cfg(test)]
+    pub fn next(&self) -> u32 {
+        ral::read_reg!(crate::td, self, NEXT)
+    }
+
+    /// Complete the transfer as if the controller received `received` bytes
+    #[cfg(test)]
+    pub fn simulate_completion(&mut self, received: usize) {
+        let remaining = self.last_transfer_size - received;
+        ral::modify_reg!(crate::td, self, TOKEN, TOTAL_BYTES: remaining as u32, STATUS: 0);
+    }
+
+    /// Complete the transfer with the `status` error bits
+    #[cfg(test)]
+    pub fn simulate_status(&mut self, status: Status) {
+        ral::modify_reg!(crate::td, self, TOKEN, STATUS: status.bits());
     }
 
     /// Read the status of the current / previous transfer
@@ -98,16 +142,28 @@ impl Td {
         ral::modify_reg!(crate::td, self, TOKEN, IOC: ioc as u32);
     }
 
-    /// Clean and invalidate this TD from DCache
-    pub fn clean_invalidate_dcache(&self) {
-        crate::cache::clean_invalidate_dcache_by_address(
-            &self as *const _ as usize,
+    /// Set the number of packets that this TD transmits per transaction
+    ///
+    /// Only meaningful for isochronous IN endpoints, where this overrides the
+    /// QH's multiplier. Use zero for all other endpoints. Clamps `mult` to 3.
+    pub fn set_mult_override(&mut self, mult: usize) {
+        ral::modify_reg!(crate::td, self, TOKEN, MULTO: mult.min(3) as u32);
+    }
+
+    /// Clean and invalidate this TD from DCache, if `policy` allows it
+    pub fn clean_invalidate_dcache(&self, policy: CachePolicy) {
+        policy.clean_invalidate_dcache_by_address(
+            self as *const _ as usize,
             core::mem::size_of_val(self),
         );
     }
 }
 
 bitflags::bitflags! {
+    /// TD status
+    ///
+    /// For isochronous endpoints, a transaction error indicates a missed
+    /// (micro)frame, and a data buffer error indicates an overrun or underrun.
     pub(crate) struct Status : u32 {
         const ACTIVE = TOKEN::STATUS::RW::ACTIVE;
         const HALTED = TOKEN::STATUS::RW::HALTED;
@@ -129,6 +185,13 @@ mod TOKEN {
         pub mod R {}
         pub mod W {}
     }
+    pub mod MULTO {
+        pub const offset: u32 = 10;
+        pub const mask: u32 = 0b11 << offset;
+        pub mod RW {}
+        pub mod R {}
+        pub mod W {}
+    }
     pub mod IOC {
         pub const offset: u32 = 15;
         pub const mask: u32 = 1 << offset;
@@ -146,9 +209,28 @@ mod TOKEN {
 }
 
 #[cfg(test)]
+#[allow(clippy::legacy_numeric_constants)]
 mod test {
     use super::Td;
-    use crate::ral;
+    use crate::{
+        cache::{CachePolicy, Operation, recorder},
+        ral,
+    };
+
+    #[test]
+    fn clean_invalidate_dcache_address() {
+        let td = Td::new();
+        recorder::take();
+        td.clean_invalidate_dcache(CachePolicy::Maintain);
+        assert_eq!(
+            recorder::take(),
+            [(
+                Operation::CleanInvalidate,
+                &td as *const Td as usize,
+                core::mem::size_of::<Td>()
+            )]
+        );
+    }
 
     #[test]
     fn terminate() {
@@ -181,6 +263,15 @@ mod test {
         assert_eq!(td.TOKEN.read(), 1 << 15);
     }
 
+    #[test]
+    fn mult_override() {
+        let mut td = Td::new();
+        td.set_mult_override(2);
+        assert_eq!(td.TOKEN.read(), 2 << 10);
+        td.set_mult_override(7);
+        assert_eq!(td.TOKEN.read(), 3 << 10);
+    }
+
     #[test]
     fn total_bytes() {
         let mut td = Td::new();